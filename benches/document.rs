@@ -0,0 +1,102 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use nicknamedb::Document;
+use tokio::runtime::{Builder, Runtime};
+
+const PAIR_COUNTS: [usize; 3] = [0, 5, 20];
+const KEYS: &str = "abcdefghijklmnopqrst";
+/// Cycled through when populating documents. Most values in the wild are a
+/// few characters long since they have to share 32 characters with the name.
+const VALUE_LENGTHS: [usize; 4] = [1, 2, 1, 0];
+/// What the `insert` benchmark adds, `^zFOO`.
+const INSERTED_LEN: usize = 5;
+
+/// Up to 5 pairs, the fixture leaves room for the `insert` benchmark. Even
+/// with empty values 20 pairs take 40 characters, past the nickname limit, so
+/// their values are left empty and `insert` and `delete` encode the whole
+/// name before it is rejected for its length. With no pairs, `delete` fails
+/// on the missing key. The results are not unwrapped for that reason.
+fn populated(pairs: usize) -> Document {
+    let fits = pairs <= 5;
+    let mut name = "m ".to_owned();
+    for (i, key) in KEYS.chars().take(pairs).enumerate() {
+        name.push('^');
+        name.push(key);
+        if fits {
+            name.push_str(&"x".repeat(VALUE_LENGTHS[i % VALUE_LENGTHS.len()]));
+        }
+    }
+
+    let document = Document::try_from_nickname(name, '^').expect("fixture");
+    assert_eq!(document.len(), pairs);
+    assert_eq!(fits, document.remaining_capacity() >= INSERTED_LEN);
+    document
+}
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().build().expect("runtime")
+}
+
+fn insert(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("insert");
+    for pairs in &PAIR_COUNTS {
+        let document = populated(*pairs);
+        group.bench_with_input(BenchmarkId::from_parameter(pairs), pairs, |b, _| {
+            b.iter_batched(
+                || document.clone(),
+                |mut document| {
+                    runtime.block_on(async move { black_box(document.insert('z', "FOO").await) })
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn fetch(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("fetch");
+    for pairs in &PAIR_COUNTS {
+        let document = populated(*pairs);
+        group.bench_with_input(BenchmarkId::from_parameter(pairs), pairs, |b, _| {
+            b.iter(|| black_box(runtime.block_on(document.fetch('a'))))
+        });
+    }
+    group.finish();
+}
+
+fn delete(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("delete");
+    for pairs in &PAIR_COUNTS {
+        let document = populated(*pairs);
+        group.bench_with_input(BenchmarkId::from_parameter(pairs), pairs, |b, _| {
+            b.iter_batched(
+                || document.clone(),
+                |mut document| {
+                    runtime.block_on(async move {
+                        black_box(document.delete::<String>('a', None).await)
+                    })
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn fetch_all(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("fetch_all");
+    for pairs in &PAIR_COUNTS {
+        let document = populated(*pairs);
+        group.bench_with_input(BenchmarkId::from_parameter(pairs), pairs, |b, _| {
+            b.iter(|| black_box(runtime.block_on(document.fetch_all())))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, fetch, delete, fetch_all);
+criterion_main!(benches);
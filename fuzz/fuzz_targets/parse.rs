@@ -0,0 +1,29 @@
+#![no_main]
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use nicknamedb::Document;
+
+fuzz_target!(|input: (char, String, char)| {
+    let (prefix, name, key) = input;
+    let document = match Document::try_new(name, prefix) {
+        Ok(document) => document,
+        Err(_) => return,
+    };
+
+    block_on(async {
+        let entries = document.fetch_all().await;
+        // With a key encoded twice fetch sees the first value and fetch_all
+        // the last, try_from_nickname rejects such names.
+        let has_duplicates = document.iter().count() != document.len();
+        for (key, value) in &entries {
+            assert!(document.exists(*key));
+            if !has_duplicates {
+                assert_eq!(document.fetch(*key).await.as_deref(), Some(value.as_str()));
+            }
+        }
+
+        assert_eq!(document.exists(key), document.fetch(key).await.is_some());
+        assert_eq!(entries.contains_key(&key), document.exists(key));
+    });
+});
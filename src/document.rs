@@ -1,51 +1,828 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "history")]
+use std::collections::{BTreeSet, VecDeque};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use chrono::{DateTime, Duration, Utc};
-use futures::lock::Mutex;
-use regex::Regex;
+use regex::{CaptureMatches, Regex};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::RwLock;
 
-#[derive(Debug)]
+pub const DEFAULT_PREFIX: char = '^';
+pub const MAX_NICKNAME_LEN: usize = 32;
+const MULTI_DELIMITER: char = '|';
+const MULTI_ESCAPE: char = '\\';
+#[cfg(feature = "history")]
+const DEFAULT_HISTORY_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DocumentError {
+    NicknameTooLong {
+        length: usize,
+        excess: usize,
+    },
+    InvalidPrefix(char),
+    InvalidKey(char),
+    /// Returned by the `&str` key methods of the `multichar-keys` feature,
+    /// see [`Document::insert_str`].
+    InvalidStrKey(String),
+    InvalidValue(String),
+    KeyNotFound(char),
+    /// Returned by [`Document::try_insert`] with the value the key already
+    /// holds.
+    KeyExists {
+        key: char,
+        value: String,
+    },
+    KeyConflict(char),
+    Malformed(String),
+    /// Returned by [`TimeoutDocument`] when an operation did not finish in
+    /// time.
+    TimedOut,
+    /// Returned when the validator registered with
+    /// [`Document::set_validator`] rejects a value.
+    ValidationFailed(String),
+    /// Returned by [`Document::rollback`] when fewer writes are recorded than
+    /// were asked to be undone.
+    HistoryExhausted {
+        requested: usize,
+        available: usize,
+    },
+    /// Returned when a write would add a key beyond
+    /// [`Document::set_max_entries`].
+    TooManyEntries(usize),
+    /// Returned by [`Document::insert_with_ttl`] when now plus the TTL is out
+    /// of the range of a timestamp.
+    TtlOutOfRange(Duration),
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocumentError::NicknameTooLong { length, excess } => write!(
+                f,
+                "nickname would be {} characters long, {} over the limit of {}",
+                length, excess, MAX_NICKNAME_LEN
+            ),
+            DocumentError::InvalidPrefix(prefix) => write!(
+                f,
+                "invalid prefix {:?}, prefixes cannot be word, whitespace or control characters",
+                prefix
+            ),
+            DocumentError::InvalidKey(key) => write!(f, "invalid key {:?}", key),
+            DocumentError::InvalidStrKey(key) => write!(f, "invalid key {:?}", key),
+            DocumentError::InvalidValue(value) => write!(f, "invalid value {:?}", value),
+            DocumentError::KeyNotFound(key) => write!(f, "key {:?} not found", key),
+            DocumentError::KeyExists { key, value } => {
+                write!(f, "key {:?} already holds {:?}", key, value)
+            }
+            DocumentError::KeyConflict(key) => {
+                write!(f, "key {:?} has different values on both sides", key)
+            }
+            DocumentError::Malformed(name) => write!(f, "malformed nickname {:?}", name),
+            DocumentError::TimedOut => write!(f, "timed out waiting for the document"),
+            DocumentError::ValidationFailed(message) => write!(f, "validation failed: {}", message),
+            DocumentError::HistoryExhausted {
+                requested,
+                available,
+            } => write!(
+                f,
+                "cannot roll back {} writes, only {} are recorded",
+                requested, available
+            ),
+            DocumentError::TooManyEntries(max) => {
+                write!(f, "a document can hold at most {} entries", max)
+            }
+            DocumentError::TtlOutOfRange(ttl) => write!(f, "ttl {} is out of range", ttl),
+        }
+    }
+}
+
+impl Error for DocumentError {}
+
+/// What [`Document::merge`] does with a key that holds different values in
+/// both documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    Overwrite,
+    KeepExisting,
+    FailOnConflict,
+}
+
+/// What kind of change an [`NicknameDb::on_change`](crate::NicknameDb::on_change)
+/// hook is told about, or a [`HistoryEntry`] records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Delete,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DocumentDiff {
+    pub added: HashMap<char, String>,
+    pub removed: HashMap<char, String>,
+    /// Keys present on both sides with different values, mapped to `(old, new)`.
+    pub changed: HashMap<char, (String, String)>,
+}
+
+impl DocumentDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// How often a [`Document`] was written to, deleted from and read, see
+/// [`Document::operation_counts`].
+///
+/// Each call to `insert`, `insert_with_ttl`, `insert_many`, `extend`, `merge`
+/// and `insert_str` counts one insert, and each call to `delete`, `retain`,
+/// `clear` and `delete_str` one delete. `update_with` counts an insert or a
+/// delete depending on what its closure returns. Each call to `fetch`,
+/// `fetch_all` and `fetch_str` counts one fetch. Everything else built on
+/// those, like `update`, `fetch_multi`, [`Entry`], [`NamespaceView`] and
+/// [`TimeoutDocument`], counts the calls it makes, so `insert_ns` counts a
+/// fetch and an insert. Synchronous reads like `exists`, `iter`, `entries`
+/// and `keys`, whole-name changes like `set_base_name`, `rollback` and
+/// `restore_snapshot`, and dropping or committing a [`TransactionGuard`] are
+/// not counted.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationCounts {
+    pub insert_count: u64,
+    pub delete_count: u64,
+    pub fetch_count: u64,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+struct Counters {
+    insert_count: AtomicU64,
+    delete_count: AtomicU64,
+    fetch_count: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl Counters {
+    fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> OperationCounts {
+        OperationCounts {
+            insert_count: self.insert_count.load(Ordering::Relaxed),
+            delete_count: self.delete_count.load(Ordering::Relaxed),
+            fetch_count: self.fetch_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl From<OperationCounts> for Counters {
+    fn from(counts: OperationCounts) -> Self {
+        Self {
+            insert_count: counts.insert_count.into(),
+            delete_count: counts.delete_count.into(),
+            fetch_count: counts.fetch_count.into(),
+        }
+    }
+}
+
+/// A change to a single key, recorded before it was made, see
+/// [`Document::history`].
+#[cfg(feature = "history")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    /// [`ChangeKind::Insert`] if the key held a value afterwards, including
+    /// overwrites.
+    pub operation: ChangeKind,
+    pub key: char,
+    /// `None` if the key was absent.
+    pub old_value: Option<String>,
+    old_expiry: Option<i64>,
+}
+
+/// The changes made by the most recent writes to a [`Document`], oldest
+/// first.
+#[cfg(feature = "history")]
+#[derive(Debug, Clone)]
+struct History {
+    entries: VecDeque<HistoryEntry>,
+    max_depth: usize,
+}
+
+#[cfg(feature = "history")]
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            max_depth: DEFAULT_HISTORY_DEPTH,
+        }
+    }
+}
+
+#[cfg(feature = "history")]
+impl History {
+    /// Records every key whose value or expiry differs between the old and
+    /// the new pairs.
+    fn record(
+        &mut self,
+        old: &BTreeMap<char, String>,
+        old_expiries: &BTreeMap<char, i64>,
+        new: &BTreeMap<char, String>,
+        new_expiries: &BTreeMap<char, i64>,
+    ) {
+        let timestamp = Utc::now();
+        for key in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+            let old_value = old.get(key);
+            let old_expiry = old_value.and(old_expiries.get(key)).copied();
+            let new_value = new.get(key);
+            if old_value == new_value && old_expiry == new_value.and(new_expiries.get(key)).copied()
+            {
+                continue;
+            }
+
+            self.entries.push_back(HistoryEntry {
+                timestamp,
+                operation: match new_value {
+                    Some(_) => ChangeKind::Insert,
+                    None => ChangeKind::Delete,
+                },
+                key: *key,
+                old_value: old_value.cloned(),
+                old_expiry,
+            });
+        }
+
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.entries.len() > self.max_depth {
+            self.entries.pop_front();
+        }
+    }
+}
+
+fn regex_cache() -> &'static std::sync::Mutex<HashMap<char, Regex>> {
+    static REGEX_CACHE: OnceLock<std::sync::Mutex<HashMap<char, Regex>>> = OnceLock::new();
+    REGEX_CACHE.get_or_init(Default::default)
+}
+
+/// A key is a single word character, or under `multichar-keys` up to
+/// [`MAX_STR_KEY_LEN`] of them followed by a `:`.
+#[cfg(not(feature = "multichar-keys"))]
+const KEY_PATTERN: &str = r"\w";
+#[cfg(feature = "multichar-keys")]
+const KEY_PATTERN: &str = r"\w{1,4}:|\w";
+/// The longest `&str` key in characters, as counted by [`KEY_PATTERN`]. A
+/// longer run of word characters before a `:` is never read as a key, so it
+/// needs no escaping in a value.
+#[cfg(feature = "multichar-keys")]
+const MAX_STR_KEY_LEN: usize = 4;
+
+fn regex_for(prefix: char) -> Result<Regex, DocumentError> {
+    let mut regex_cache = regex_cache().lock().expect("regex cache");
+    if let Some(regex) = regex_cache.get(&prefix) {
+        return Ok(regex.clone());
+    }
+
+    let escaped = regex::escape(&prefix.to_string());
+    let regex = Regex::new(&format!(
+        r"{0}({1})((?:[^{0}]|{0}{0})*)(?:{0} ([0-9a-z]+))?",
+        escaped, KEY_PATTERN
+    ))
+    .map_err(|_| DocumentError::InvalidPrefix(prefix))?;
+    regex_cache.insert(prefix, regex.clone());
+
+    Ok(regex)
+}
+
+/// The prefix checks of [`Document::try_new`], for callers that keep a prefix
+/// to create documents with later.
+pub(crate) fn validate_prefix(prefix: char) -> Result<(), DocumentError> {
+    if is_word_char(prefix)
+        || prefix.is_whitespace()
+        || prefix.is_control()
+        || (cfg!(feature = "multichar-keys") && prefix == ':')
+    {
+        return Err(DocumentError::InvalidPrefix(prefix));
+    }
+
+    regex_for(prefix).map(|_| ())
+}
+
+/// Whether `c` matches the `\w` keys are parsed with. This is close to but
+/// not the same as [`char::is_alphanumeric`], e.g. `'½'` is alphanumeric
+/// without being a word character and `'\u{301}'` is the other way around.
+fn is_word_char(c: char) -> bool {
+    static WORD_REGEX: OnceLock<Regex> = OnceLock::new();
+    WORD_REGEX
+        .get_or_init(|| Regex::new(r"^\w$").expect("word regex"))
+        .is_match(c.encode_utf8(&mut [0; 4]))
+}
+
+/// Matches the values that would be read as the rest of a longer key if they
+/// were written right after a single character key.
+#[cfg(feature = "multichar-keys")]
+fn key_terminator_regex() -> &'static Regex {
+    static KEY_TERMINATOR_REGEX: OnceLock<Regex> = OnceLock::new();
+    KEY_TERMINATOR_REGEX.get_or_init(|| Regex::new(r"^\w{0,3}:").expect("key terminator regex"))
+}
+
+#[cfg(feature = "multichar-keys")]
+fn str_key_regex() -> &'static Regex {
+    static STR_KEY_REGEX: OnceLock<Regex> = OnceLock::new();
+    STR_KEY_REGEX.get_or_init(|| Regex::new(r"^\w+$").expect("str key regex"))
+}
+
+fn single_char(key: &str) -> Option<char> {
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(key), None) => Some(key),
+        _ => None,
+    }
+}
+
+/// The key of a parsed entry without its `:` terminator, if it is a single
+/// character.
+fn char_key(raw: &str) -> Option<char> {
+    single_char(raw.trim_end_matches(':'))
+}
+
+/// Lowercases a key, leaving it alone if its lowercase form is not a single
+/// character.
+fn fold_key(key: char) -> char {
+    let mut lowercase = key.to_lowercase();
+    match (lowercase.next(), lowercase.next()) {
+        (Some(lower), None) => lower,
+        _ => key,
+    }
+}
+
+fn to_base36(mut n: u64) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.extend(std::char::from_digit((n % 36) as u32, 36));
+        n /= 36;
+        if n == 0 {
+            break;
+        }
+    }
+
+    digits.iter().rev().collect()
+}
+
+/// How the prefix is written inside a value.
+fn escape_prefix(prefix: char) -> String {
+    [prefix, prefix].iter().collect()
+}
+
+/// Key-value pairs encoded at the end of a nickname as `<prefix><key><value>`,
+/// e.g. `menfie ^AFOO^bBAR`. Keys are always a single word character so that
+/// no separator is needed between a key and its value. A value runs until the
+/// next prefix, so it may contain spaces; a prefix inside a value is written
+/// twice, e.g. `^AX^^Y` holds `X^Y`. An entry with a TTL is followed by the
+/// prefix, a space and its expiry in epoch seconds as base 36, e.g.
+/// `^sbusy^ tro8w0`.
+///
+/// With the `multichar-keys` feature, [`Document::insert_str`] and friends
+/// also take `&str` keys of one to four word characters, written with a `:`
+/// between the key and the value, e.g. `^tz:UTC`. The `char` methods keep working and
+/// skip those entries. A single character key only gets the `:` when its
+/// value starts with up to three word characters followed by a `:`, e.g.
+/// `^a:b:c` holds `b:c` under `a`, since `^ab:c` would hold `c` under `ab`.
+/// Nicknames written without the feature can read differently with it, so
+/// the feature is opt-in and has to stay enabled once used.
 pub struct Document {
     pub name: String,
     prefix: char,
     regex: Regex,
-    last_access: Arc<Mutex<DateTime<Utc>>>,
+    case_insensitive: bool,
+    validator: Option<Validator>,
+    max_entries: Option<usize>,
+    last_read: Arc<RwLock<DateTime<Utc>>>,
+    last_written: Arc<RwLock<DateTime<Utc>>>,
+    #[cfg(feature = "metrics")]
+    counters: Counters,
+    #[cfg(feature = "history")]
+    history: History,
 }
 
+type Validator = Arc<dyn Fn(char, &str) -> Result<(), String> + Send + Sync>;
+
 impl Document {
+    /// Panics if the prefix is invalid, see [`Document::try_new`].
+    #[deprecated(note = "panics on an invalid prefix, use `Document::try_new` instead")]
     pub fn new(name: String, prefix: char) -> Self {
-        Self {
+        Self::try_new(name, prefix).expect("prefix")
+    }
+
+    pub fn with_default_prefix(name: String) -> Self {
+        Self::try_new(name, DEFAULT_PREFIX).expect("default prefix")
+    }
+
+    /// Like [`Document::try_new`], but keys are matched case-insensitively and
+    /// stored in lowercase, so `'A'` and `'a'` refer to the same entry.
+    pub fn new_case_insensitive(name: String, prefix: char) -> Result<Self, DocumentError> {
+        Ok(Self {
+            case_insensitive: true,
+            ..Self::try_new(name, prefix)?
+        })
+    }
+
+    /// Fails with [`DocumentError::InvalidPrefix`] for word characters, which
+    /// could not be told apart from keys, and for whitespace and control
+    /// characters. With `multichar-keys` the `:` key terminator is rejected
+    /// as well.
+    pub fn try_new(name: String, prefix: char) -> Result<Self, DocumentError> {
+        validate_prefix(prefix)?;
+
+        Ok(Self {
             name,
             prefix,
-            regex: Regex::new(r"(\^(?:\w)(?:[^\^\s]+)){1}").expect("regex"),
-            last_access: Arc::new(Mutex::new(Utc::now())),
+            regex: regex_for(prefix)?,
+            case_insensitive: false,
+            validator: None,
+            max_entries: None,
+            last_read: Arc::new(RwLock::new(Utc::now())),
+            last_written: Arc::new(RwLock::new(Utc::now())),
+            #[cfg(feature = "metrics")]
+            counters: Counters::default(),
+            #[cfg(feature = "history")]
+            history: History::default(),
+        })
+    }
+
+    /// Parses a nickname that may already carry encoded pairs. This is the same
+    /// as [`Document::new`], use [`Document::try_from_nickname`] to reject
+    /// nicknames whose encoding is broken.
+    #[deprecated(note = "panics on an invalid prefix, use `Document::try_from_nickname` instead")]
+    pub fn from_nickname(name: String, prefix: char) -> Self {
+        Self::try_new(name, prefix).expect("prefix")
+    }
+
+    /// Fails with [`DocumentError::Malformed`] if the prefix appears anywhere
+    /// other than at the start of a pair, or if a key is encoded twice.
+    pub fn try_from_nickname(name: String, prefix: char) -> Result<Self, DocumentError> {
+        let document = Self::try_new(name, prefix)?;
+
+        let pairs = document.iter().count();
+        if document.base_name().contains(prefix) || document.len() != pairs {
+            return Err(DocumentError::Malformed(document.name));
+        }
+
+        Ok(document)
+    }
+
+    /// Builds a document from its base name and pairs, checking them the
+    /// same way `set_base_name` and `insert_many` would.
+    pub fn from_kv(
+        base_name: String,
+        prefix: char,
+        kv: HashMap<char, String>,
+    ) -> Result<Self, DocumentError> {
+        let mut document = Self::try_new(String::new(), prefix)?;
+        document.set_base_name(&base_name)?;
+
+        let mut kv_chain = BTreeMap::new();
+        for (key, value) in kv {
+            let key = document.normalize_key(key);
+            document.validate_key(key)?;
+            document.validate_value(key, &value)?;
+            kv_chain.insert(key, value);
+        }
+        document.push_kv(kv_chain)?;
+        #[cfg(feature = "history")]
+        document.history.entries.clear();
+
+        Ok(document)
+    }
+
+    pub async fn insert<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        let value = value.into();
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "nicknamedb::insert",
+            document.base_name = %self.base_name(),
+            key = %key,
+            value = %value,
+        );
+
+        let future = self.insert_expiring(key, value, None);
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(future, span);
+        future.await
+    }
+
+    /// Inserts an entry that reads as absent once `ttl` has passed. Expired
+    /// entries stay in the name until [`Document::purge_expired`] or the next
+    /// write. Any later write of a value to the key, through `insert`,
+    /// `update_with`, `merge` or the methods built on them, drops the TTL.
+    pub async fn insert_with_ttl<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+        ttl: Duration,
+    ) -> Result<(), DocumentError> {
+        let expiry = Utc::now()
+            .checked_add_signed(ttl)
+            .ok_or(DocumentError::TtlOutOfRange(ttl))?
+            .timestamp()
+            .max(0);
+        self.insert_expiring(key, value.into(), Some(expiry)).await
+    }
+
+    async fn insert_expiring(
+        &mut self,
+        key: char,
+        value: String,
+        expiry: Option<i64>,
+    ) -> Result<(), DocumentError> {
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.insert_count);
+
+        let key = self.normalize_key(key);
+        self.validate_key(key)?;
+        self.validate_value(key, &value)?;
+
+        let mut kv_chain = self.kv_chain();
+        kv_chain.insert(key, value);
+
+        let mut expiries = self.expiries();
+        match expiry {
+            Some(expiry) => expiries.insert(key, expiry),
+            None => expiries.remove(&key),
+        };
+
+        self.push_named_kv(self.base_name(), kv_chain, expiries)
+    }
+
+    pub async fn insert_if_absent<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<bool, DocumentError> {
+        if self.exists(key) {
+            self.mark_read().await;
+            return Ok(false);
+        }
+
+        self.insert(key, value).await?;
+        Ok(true)
+    }
+
+    /// Like [`Document::insert_if_absent`], but fails with
+    /// [`DocumentError::KeyExists`] carrying the current value if the key is
+    /// already set.
+    pub async fn try_insert<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        if let Some(existing) = self.find(key).map(Cow::into_owned) {
+            self.mark_read().await;
+            return Err(DocumentError::KeyExists {
+                key: self.normalize_key(key),
+                value: existing,
+            });
+        }
+
+        self.insert(key, value).await
+    }
+
+    /// Overwrites the value of an existing key. Returns `Ok(false)` without
+    /// touching the document if the key is absent.
+    pub async fn update<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<bool, DocumentError> {
+        if !self.exists(key) {
+            self.mark_read().await;
+            return Ok(false);
+        }
+
+        self.insert(key, value).await?;
+        Ok(true)
+    }
+
+    /// Inserts `value` and returns the value it replaced, if any.
+    pub async fn replace<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<Option<String>, DocumentError> {
+        let old = self.find(key).map(Cow::into_owned);
+        self.insert(key, value).await?;
+
+        Ok(old)
+    }
+
+    /// Read-modify-write of a single key: `f` gets the current value, returning
+    /// `Some` stores the new value and returning `None` deletes the key.
+    pub async fn update_with<F: FnOnce(Option<&str>) -> Option<String>>(
+        &mut self,
+        key: char,
+        f: F,
+    ) -> Result<(), DocumentError> {
+        self.mark_written().await;
+
+        let key = self.normalize_key(key);
+        let mut kv_chain = self.kv_chain();
+        let mut expiries = self.expiries();
+        match f(kv_chain.get(&key).map(String::as_str)) {
+            Some(value) => {
+                #[cfg(feature = "metrics")]
+                Counters::increment(&self.counters.insert_count);
+                self.validate_key(key)?;
+                self.validate_value(key, &value)?;
+                kv_chain.insert(key, value);
+                expiries.remove(&key);
+            }
+            None => {
+                #[cfg(feature = "metrics")]
+                Counters::increment(&self.counters.delete_count);
+                kv_chain.remove(&key);
+            }
+        }
+
+        self.push_named_kv(self.base_name(), kv_chain, expiries)
+    }
+
+    /// Inserts every pair or, if any of them is invalid or the result would
+    /// not fit, none of them.
+    pub async fn insert_many<T: Into<String>>(
+        &mut self,
+        pairs: impl IntoIterator<Item = (char, T)>,
+    ) -> Result<(), DocumentError> {
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.insert_count);
+
+        let mut kv_chain = self.kv_chain();
+        let mut expiries = self.expiries();
+        for (key, value) in pairs {
+            let key = self.normalize_key(key);
+            let value = value.into();
+            self.validate_key(key)?;
+            self.validate_value(key, &value)?;
+            kv_chain.insert(key, value);
+            expiries.remove(&key);
+        }
+
+        self.push_named_kv(self.base_name(), kv_chain, expiries)
+    }
+
+    /// Same as [`Document::insert_many`], named after [`Extend::extend`].
+    pub async fn extend<I: IntoIterator<Item = (char, T)>, T: Into<String>>(
+        &mut self,
+        iter: I,
+    ) -> Result<(), DocumentError> {
+        self.insert_many(iter).await
+    }
+
+    /// Copies the pairs of `other` into this document, keeping its base name.
+    /// Nothing is changed if the merge fails.
+    pub async fn merge(
+        &mut self,
+        other: &Document,
+        on_conflict: ConflictStrategy,
+    ) -> Result<(), DocumentError> {
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.insert_count);
+
+        let mut kv_chain = self.kv_chain();
+        let mut expiries = self.expiries();
+        for (key, value) in other.kv_chain() {
+            let key = self.normalize_key(key);
+            match kv_chain.get(&key) {
+                Some(existing) if *existing == value => continue,
+                Some(_) if on_conflict == ConflictStrategy::KeepExisting => continue,
+                Some(_) if on_conflict == ConflictStrategy::FailOnConflict => {
+                    return Err(DocumentError::KeyConflict(key));
+                }
+                _ => {}
+            }
+
+            self.validate_key(key)?;
+            self.validate_value(key, &value)?;
+            kv_chain.insert(key, value);
+            expiries.remove(&key);
         }
+
+        self.push_named_kv(self.base_name(), kv_chain, expiries)
+    }
+
+    /// Same as [`Document::merge`] with [`ConflictStrategy::KeepExisting`].
+    pub async fn merge_keep(&mut self, other: &Document) -> Result<(), DocumentError> {
+        self.merge(other, ConflictStrategy::KeepExisting).await
+    }
+
+    /// Stores `values` under a single key, separated by `|`. Literal `|` and
+    /// `\` inside a value are escaped with a backslash. Every value after the
+    /// first costs one more character of the nickname for its separator.
+    pub async fn insert_multi<T: Into<String>>(
+        &mut self,
+        key: char,
+        values: Vec<T>,
+    ) -> Result<(), DocumentError> {
+        let value = values
+            .into_iter()
+            .map(|value| {
+                value.into().chars().fold(String::new(), |mut escaped, c| {
+                    if c == MULTI_DELIMITER || c == MULTI_ESCAPE {
+                        escaped.push(MULTI_ESCAPE);
+                    }
+                    escaped.push(c);
+                    escaped
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(&MULTI_DELIMITER.to_string());
+
+        self.insert(key, value).await
     }
 
-    pub async fn insert<T: Into<String>>(&mut self, key: char, value: T) {
-        *self.last_access.lock().await = Utc::now();
+    /// Removes the first occurrence of `value` from the list under `key`, and
+    /// the key itself once the list is empty.
+    pub async fn delete_multi(&mut self, key: char, value: &str) -> Result<(), DocumentError> {
+        let key = self.normalize_key(key);
+        self.validate_key(key)?;
+        if !self.exists(key) {
+            return Err(DocumentError::KeyNotFound(key));
+        }
+
+        let mut values = self.fetch_multi(key).await;
+        match values.iter().position(|v| v == value) {
+            Some(index) => values.remove(index),
+            None => return Ok(()),
+        };
 
-        let mut kv_chain = self.fetch_all().unwrap_or_else(HashMap::new);
-        kv_chain.insert(key, value.into());
+        if values.is_empty() {
+            self.delete::<String>(key, None).await
+        } else {
+            self.insert_multi(key, values).await
+        }
+    }
+
+    pub async fn delete<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: Option<T>,
+    ) -> Result<(), DocumentError> {
+        let value = value.map(Into::into);
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "nicknamedb::delete",
+            document.base_name = %self.base_name(),
+            key = %key,
+            value = ?value,
+        );
 
-        self.push_kv(kv_chain);
+        let future = self.delete_entry(key, value);
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(future, span);
+        future.await
     }
 
-    pub async fn delete<T: Into<String> + Clone>(&mut self, key: char, value: Option<T>) {
-        *self.last_access.lock().await = Utc::now();
+    async fn delete_entry(
+        &mut self,
+        key: char,
+        value: Option<String>,
+    ) -> Result<(), DocumentError> {
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.delete_count);
+        let key = self.normalize_key(key);
+        self.validate_key(key)?;
         if !self.exists(key) {
-            return;
+            return Err(DocumentError::KeyNotFound(key));
         }
 
         let kv_chain = self
-            .fetch_all()
-            .unwrap()
+            .kv_chain()
             .iter()
             .filter(|kv| {
                 if *kv.0 == key {
-                    if let Some(value) = value.clone() {
-                        return *kv.1 != value.into();
+                    if let Some(value) = &value {
+                        return kv.1 != value;
                     }
 
                     return false;
@@ -54,100 +831,2763 @@ impl Document {
                 true
             })
             .map(|kv| (*kv.0, kv.1.clone()))
-            .collect::<HashMap<_, _>>();
+            .collect::<BTreeMap<_, _>>();
 
-        self.push_kv(kv_chain);
+        self.push_kv(kv_chain)
     }
 
-    pub async fn fetch(&self, key: char) -> Option<&str> {
-        *self.last_access.lock().await = Utc::now();
-        if !self.exists(key) {
-            return None;
+    /// Keeps only the pairs for which `f` returns `true`.
+    pub async fn retain<F: FnMut(char, &str) -> bool>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), DocumentError> {
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.delete_count);
+
+        let mut kv_chain = self.kv_chain();
+        kv_chain.retain(|key, value| f(*key, value));
+
+        self.push_kv(kv_chain)
+    }
+
+    pub async fn clear(&mut self) {
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.delete_count);
+
+        self.set_name(self.base_name());
+    }
+
+    pub fn entry(&mut self, key: char) -> Entry<'_> {
+        Entry {
+            document: self,
+            key,
+            modify: None,
+        }
+    }
+
+    pub async fn fetch(&self, key: char) -> Option<Cow<'_, str>> {
+        let future = async move {
+            self.mark_read().await;
+            #[cfg(feature = "metrics")]
+            Counters::increment(&self.counters.fetch_count);
+            self.find(key)
+        };
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(
+            future,
+            tracing::debug_span!(
+                "nicknamedb::fetch",
+                document.base_name = %self.base_name(),
+                key = %key,
+            ),
+        );
+        future.await
+    }
+
+    pub async fn fetch_or_default<'a>(&'a self, key: char, default: &'a str) -> Cow<'a, str> {
+        self.fetch(key).await.unwrap_or(Cow::Borrowed(default))
+    }
+
+    /// Fetches `key` and parses it, returning `None` if the key is missing or
+    /// the value does not parse.
+    pub async fn fetch_parsed<T: FromStr>(&self, key: char) -> Option<T> {
+        self.fetch(key).await?.parse().ok()
+    }
+
+    pub async fn fetch_or_insert<T: Into<String>>(
+        &mut self,
+        key: char,
+        default: T,
+    ) -> Result<Cow<'_, str>, DocumentError> {
+        self.insert_if_absent(key, default).await?;
+
+        Ok(self.fetch(key).await.unwrap_or_default())
+    }
+
+    pub async fn fetch_multi(&self, key: char) -> Vec<String> {
+        let value = match self.fetch(key).await {
+            Some(value) => value,
+            None => return Vec::new(),
+        };
+
+        let mut values = Vec::new();
+        let mut current = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                MULTI_ESCAPE => current.extend(chars.next()),
+                MULTI_DELIMITER => values.push(std::mem::take(&mut current)),
+                c => current.push(c),
+            }
         }
+        values.push(current);
+
+        values
+    }
 
-        let result = self.regex.find_iter(&self.name).find(|mat| {
-            let mut kv = mat.as_str().to_string();
-            kv.remove(0);
-            let matkey = kv.remove(0);
+    /// Stores `value` under `key` inside `namespace`. A namespace is a
+    /// [`Document::insert_multi`] list under the namespace key, where each
+    /// item is a key followed by its value, e.g. `^gAFOO|bBAR`.
+    pub async fn insert_ns<T: Into<String>>(
+        &mut self,
+        namespace: char,
+        key: char,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        let key = self.normalize_key(key);
+        let value = value.into();
+        self.validate_key(key)?;
+        self.validate_value(key, &value)?;
 
-            matkey == key
-        });
+        let mut entries = self.namespace_entries(namespace).await;
+        entries.insert(key, value);
+        self.set_namespace_entries(namespace, entries).await
+    }
 
-        if let Some(result) = result {
-            Some(result.as_str().split_at(2).1)
-        } else {
-            None
+    pub async fn fetch_ns(&self, namespace: char, key: char) -> Option<String> {
+        self.namespace_entries(namespace)
+            .await
+            .remove(&self.normalize_key(key))
+    }
+
+    /// Removes `key` from `namespace`, and the namespace itself once it is
+    /// empty.
+    pub async fn delete_ns(&mut self, namespace: char, key: char) -> Result<(), DocumentError> {
+        let key = self.normalize_key(key);
+        let mut entries = self.namespace_entries(namespace).await;
+        if entries.remove(&key).is_none() {
+            return Err(DocumentError::KeyNotFound(key));
+        }
+
+        self.set_namespace_entries(namespace, entries).await
+    }
+
+    pub fn namespace(&mut self, namespace: char) -> NamespaceView<'_> {
+        NamespaceView {
+            document: self,
+            namespace,
+        }
+    }
+
+    pub fn contains_value(&self, value: &str) -> bool {
+        self.key_of(value).is_some()
+    }
+
+    /// The first key, in encoding order, that holds `value`.
+    pub fn key_of(&self, value: &str) -> Option<char> {
+        self.iter().find(|kv| kv.1 == value).map(|kv| kv.0)
+    }
+
+    /// Describes how to get from `self` to `other`.
+    pub fn diff(&self, other: &Document) -> DocumentDiff {
+        let old = self.kv_chain();
+        let mut new = other.kv_chain();
+
+        let mut diff = DocumentDiff::default();
+        for (key, old_value) in old {
+            match new.remove(&key) {
+                Some(new_value) if new_value == old_value => {}
+                Some(new_value) => {
+                    diff.changed.insert(key, (old_value, new_value));
+                }
+                None => {
+                    diff.removed.insert(key, old_value);
+                }
+            }
         }
+        diff.added.extend(new);
+
+        diff
+    }
+
+    /// The name without any encoded pairs.
+    pub fn base_name(&self) -> String {
+        self.regex.replace_all(&self.name, "").trim().to_owned()
+    }
+
+    /// Replaces the base name while keeping every encoded pair.
+    pub fn set_base_name(&mut self, new_name: &str) -> Result<(), DocumentError> {
+        if self.regex.is_match(new_name) {
+            return Err(DocumentError::InvalidValue(new_name.to_owned()));
+        }
+
+        self.push_named_kv(new_name.trim().to_owned(), self.kv_chain(), self.expiries())
+    }
+
+    /// Re-encodes every pair under `new_prefix`. Fails if the new prefix is
+    /// invalid or already appears in the base name, or if escaping it inside
+    /// values makes the name too long.
+    pub fn migrate_prefix(&mut self, new_prefix: char) -> Result<(), DocumentError> {
+        let mut migrated = Document::try_new(String::new(), new_prefix)?;
+        let base_name = self.base_name();
+        if base_name.contains(new_prefix) {
+            return Err(DocumentError::InvalidValue(base_name));
+        }
+
+        migrated.push_entries(base_name, self.kv_chain(), self.expiries(), self.str_kv())?;
+        self.name = migrated.name;
+        self.prefix = new_prefix;
+        self.regex = migrated.regex;
+
+        Ok(())
+    }
+
+    pub fn encoded_name(&self) -> &str {
+        &self.name
     }
 
     pub fn exists(&self, key: char) -> bool {
-        self.name.contains(&format!("{}{}", self.prefix, key))
+        self.find(key).is_some()
     }
 
-    pub async fn since_last_access(&self) -> Duration {
-        Utc::now() - *self.last_access.lock().await
+    /// Stores `value` under a key of up to four word characters,
+    /// failing with [`DocumentError::InvalidStrKey`] otherwise, encoded as
+    /// `^key:value`. A single character key is the same entry as the `char`
+    /// key. Entries under longer keys have no TTL and are not passed to the
+    /// validator.
+    #[cfg(feature = "multichar-keys")]
+    pub async fn insert_str<T: Into<String>>(
+        &mut self,
+        key: &str,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        let key = self.normalize_str_key(key);
+        self.validate_str_key(&key)?;
+        let value = value.into();
+        if let Some(key) = single_char(&key) {
+            return self.insert(key, value).await;
+        }
+
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.insert_count);
+        if value.ends_with(char::is_whitespace) {
+            return Err(DocumentError::InvalidValue(value));
+        }
+
+        let mut str_kv = self.str_kv();
+        str_kv.insert(key, value);
+        self.push_entries(self.base_name(), self.kv_chain(), self.expiries(), str_kv)
     }
 
-    fn fetch_all(&self) -> Option<HashMap<char, String>> {
-        let nick = &self.name;
-        if !self.regex.is_match(&nick) {
-            return None;
+    /// Fails with [`DocumentError::InvalidStrKey`] for the keys
+    /// [`Document::insert_str`] rejects.
+    #[cfg(feature = "multichar-keys")]
+    pub async fn fetch_str(&self, key: &str) -> Result<Option<String>, DocumentError> {
+        let key = self.normalize_str_key(key);
+        self.validate_str_key(&key)?;
+        if let Some(key) = single_char(&key) {
+            return Ok(self.fetch(key).await.map(Cow::into_owned));
         }
 
-        Some(
-            self.regex
-                .find_iter(&nick)
-                .map(|mat| {
-                    let mut kv = mat.as_str().to_string();
-                    kv.remove(0);
-                    let key = kv.remove(0);
-                    (key, kv)
-                })
-                .collect::<HashMap<_, _>>(),
-        )
+        self.mark_read().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.fetch_count);
+        Ok(self.str_kv().remove(&key))
     }
 
-    fn push_kv(&mut self, kv: HashMap<char, String>) {
-        let kv_string = kv
-            .iter()
-            .map(|kv| "^".to_owned() + &kv.0.to_string() + kv.1)
-            .collect::<String>();
+    /// Removes `key` and returns its value, or `None` if it was absent.
+    #[cfg(feature = "multichar-keys")]
+    pub async fn delete_str(&mut self, key: &str) -> Result<Option<String>, DocumentError> {
+        let key = self.normalize_str_key(key);
+        self.validate_str_key(&key)?;
+        if let Some(key) = single_char(&key) {
+            let old = self.find(key).map(Cow::into_owned);
+            if old.is_some() {
+                self.delete::<String>(key, None).await?;
+            }
+
+            return Ok(old);
+        }
 
-        let name_current = &self.name;
-        let name_new = self.regex.replace_all(&name_current, "");
-        let name_new = name_new.to_string().trim().to_owned() + " " + &kv_string;
+        self.mark_written().await;
+        #[cfg(feature = "metrics")]
+        Counters::increment(&self.counters.delete_count);
+        let mut str_kv = self.str_kv();
+        let old = str_kv.remove(&key);
+        if old.is_some() {
+            self.push_entries(self.base_name(), self.kv_chain(), self.expiries(), str_kv)?;
+        }
 
-        self.name = name_new;
+        Ok(old)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Fails with [`DocumentError::InvalidStrKey`] like
+    /// [`Document::fetch_str`].
+    #[cfg(feature = "multichar-keys")]
+    pub fn exists_str(&self, key: &str) -> Result<bool, DocumentError> {
+        let key = self.normalize_str_key(key);
+        self.validate_str_key(&key)?;
+        Ok(match single_char(&key) {
+            Some(key) => self.exists(key),
+            None => self.str_kv().contains_key(&key),
+        })
+    }
 
-    #[tokio::test]
-    async fn insert() {
-        let mut document = Document::new("menfie".to_owned(), '^');
-        document.insert('A', "FOO").await;
-        document.insert('b', "BAR").await;
-        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+    pub async fn fetch_all(&self) -> HashMap<char, String> {
+        let future = async move {
+            self.mark_read().await;
+            #[cfg(feature = "metrics")]
+            Counters::increment(&self.counters.fetch_count);
+            self.entries()
+        };
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(
+            future,
+            tracing::debug_span!(
+                "nicknamedb::fetch_all",
+                document.base_name = %self.base_name(),
+            ),
+        );
+        future.await
     }
 
-    #[tokio::test]
-    async fn delete() {
-        let mut document = Document::new("menfie".to_owned(), '^');
-        document.insert('A', "FOO").await;
-        document.insert('b', "BAR").await;
-        document.delete::<String>('A', None).await;
-        document.insert('A', "FOO").await;
-        document.delete('A', Some("FOO")).await;
-        assert_eq!(document.name, "menfie ^bBAR");
+    pub fn entries(&self) -> HashMap<char, String> {
+        self.kv_chain().into_iter().collect()
     }
 
-    #[tokio::test]
-    async fn fetch() {
-        let mut document = Document::new("menfie".to_owned(), '^');
-        document.insert('A', "FOO").await;
-        assert_eq!(document.fetch('A').await, Some("FOO"));
+    /// Iterates over the stored pairs in the order they appear in the name,
+    /// which is sorted by key for anything written by this crate.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            prefix: self.prefix,
+            case_insensitive: self.case_insensitive,
+            now: Utc::now().timestamp(),
+            captures: self.regex.captures_iter(&self.name),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = char> {
+        let mut keys = self.iter().map(|kv| kv.0).collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+
+        keys.into_iter()
+    }
+
+    pub fn values(&self) -> Vec<String> {
+        self.kv_chain().into_values().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Removes expired entries from the name. They already read as absent.
+    pub fn purge_expired(&mut self) -> Result<(), DocumentError> {
+        self.push_kv(self.kv_chain())
+    }
+
+    /// Number of characters that can still be appended as encoded entries
+    /// (prefix and key included) before hitting the nickname length limit.
+    pub fn remaining_capacity(&self) -> usize {
+        let used = if self.is_empty() {
+            self.name.trim().chars().count() + 1
+        } else {
+            self.name.chars().count()
+        };
+
+        MAX_NICKNAME_LEN.saturating_sub(used)
+    }
+
+    /// Registers a check that every written value has to pass, replacing any
+    /// previous one. Clones share the validator.
+    pub fn set_validator<F>(&mut self, validator: F)
+    where
+        F: Fn(char, &str) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.validator = Some(Arc::new(validator));
+    }
+
+    /// Limits how many keys the document may hold. Writes that would add a key
+    /// beyond the limit fail with [`DocumentError::TooManyEntries`], while
+    /// overwriting or deleting existing keys is always allowed.
+    pub fn set_max_entries(&mut self, max_entries: Option<usize>) {
+        self.max_entries = max_entries;
+    }
+
+    /// Marks the document as used without reading or writing any entry.
+    pub async fn touch(&self) {
+        self.mark_read().await;
+    }
+
+    /// The later of the last read and the last write.
+    pub async fn last_accessed(&self) -> DateTime<Utc> {
+        let last_read = *self.last_read.read().await;
+        last_read.max(*self.last_written.read().await)
+    }
+
+    /// Same as [`Document::last_accessed`].
+    pub async fn last_access_at(&self) -> DateTime<Utc> {
+        self.last_accessed().await
+    }
+
+    pub async fn since_last_access(&self) -> Duration {
+        Utc::now() - self.last_accessed().await
+    }
+
+    /// Like [`Document::last_accessed`] for callers that can't await, falling
+    /// back to now while a timestamp is being written.
+    #[cfg(feature = "file")]
+    pub(crate) fn last_accessed_now(&self) -> DateTime<Utc> {
+        let read = |time: &RwLock<DateTime<Utc>>| {
+            time.try_read()
+                .map(|time| *time)
+                .unwrap_or_else(|_| Utc::now())
+        };
+
+        read(&self.last_read).max(read(&self.last_written))
+    }
+
+    /// Used when a document is restored from disk with its old access time.
+    #[cfg(feature = "file")]
+    pub(crate) fn set_last_accessed(&mut self, at: DateTime<Utc>) {
+        self.last_read = Arc::new(RwLock::new(at));
+        self.last_written = Arc::new(RwLock::new(at));
+    }
+
+    /// Whether the document has been idle for longer than `threshold`.
+    pub async fn is_stale(&self, threshold: Duration) -> bool {
+        self.since_last_access().await > threshold
+    }
+
+    /// Time since the last write attempt, reads do not count.
+    pub async fn since_last_write(&self) -> Duration {
+        Utc::now() - *self.last_written.read().await
+    }
+
+    async fn mark_read(&self) {
+        *self.last_read.write().await = Utc::now();
+    }
+
+    async fn mark_written(&self) {
+        *self.last_written.write().await = Utc::now();
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn operation_counts(&self) -> OperationCounts {
+        self.counters.snapshot()
+    }
+
+    /// Undoes the last `n` recorded changes, newest first, putting back the old
+    /// values along with their TTLs. A write that changes several keys, like
+    /// [`Document::insert_many`], records one change per key. Fails with
+    /// [`DocumentError::HistoryExhausted`] and changes nothing if fewer are
+    /// recorded. Changes to the base name, to `&str` keys and to the public
+    /// `name` field are not recorded.
+    #[cfg(feature = "history")]
+    pub async fn rollback(&mut self, n: usize) -> Result<(), DocumentError> {
+        let available = self.history.entries.len();
+        if n > available {
+            return Err(DocumentError::HistoryExhausted {
+                requested: n,
+                available,
+            });
+        }
+
+        let mut kv_chain = self.kv_chain();
+        let mut expiries = self.expiries();
+        for entry in self.history.entries.iter().rev().take(n) {
+            match &entry.old_value {
+                Some(value) => {
+                    kv_chain.insert(entry.key, value.clone());
+                    match entry.old_expiry {
+                        Some(expiry) => expiries.insert(entry.key, expiry),
+                        None => expiries.remove(&entry.key),
+                    };
+                }
+                None => {
+                    kv_chain.remove(&entry.key);
+                }
+            }
+        }
+
+        // Undoing is not recorded as another change.
+        let mut history = std::mem::take(&mut self.history);
+        let result = self.push_named_kv(self.base_name(), kv_chain, expiries);
+        if result.is_ok() {
+            history.entries.truncate(available - n);
+            self.mark_written().await;
+        }
+        self.history = history;
+
+        result
+    }
+
+    /// The changes [`Document::rollback`] can undo, oldest first.
+    #[cfg(feature = "history")]
+    pub fn history(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.history.entries.iter()
+    }
+
+    /// How many changes [`Document::rollback`] can undo.
+    #[cfg(feature = "history")]
+    pub fn history_len(&self) -> usize {
+        self.history.entries.len()
+    }
+
+    /// Sets how many changes are remembered, 16 by default, dropping the
+    /// oldest ones above `depth`.
+    #[cfg(feature = "history")]
+    pub fn set_max_history_depth(&mut self, depth: usize) {
+        self.history.max_depth = depth;
+        self.history.truncate();
+    }
+
+    /// Captures the encoded pairs and base name so they can be put back with
+    /// [`Document::restore_snapshot`].
+    pub fn snapshot(&self) -> DocumentSnapshot {
+        DocumentSnapshot {
+            name: self.name.clone(),
+            prefix: self.prefix,
+            regex: self.regex.clone(),
+            #[cfg(feature = "history")]
+            history: self.history.entries.clone(),
+        }
+    }
+
+    pub async fn restore_snapshot(&mut self, snapshot: DocumentSnapshot) {
+        self.mark_written().await;
+
+        self.restore(snapshot);
+    }
+
+    /// Starts a set of changes that is undone when the guard is dropped
+    /// without calling [`TransactionGuard::commit`].
+    pub fn transaction(&mut self) -> TransactionGuard<'_> {
+        TransactionGuard {
+            snapshot: Some(self.snapshot()),
+            document: self,
+        }
+    }
+
+    /// Wraps the document so that every async operation gives up with
+    /// [`DocumentError::TimedOut`] after `timeout`.
+    pub fn with_timeout(self, timeout: std::time::Duration) -> TimeoutDocument {
+        TimeoutDocument {
+            document: self,
+            timeout,
+        }
+    }
+
+    fn find(&self, key: char) -> Option<Cow<'_, str>> {
+        let key = self.normalize_key(key);
+        self.iter().find(|kv| kv.0 == key).map(|kv| kv.1)
+    }
+
+    fn normalize_key(&self, key: char) -> char {
+        if self.case_insensitive {
+            fold_key(key)
+        } else {
+            key
+        }
+    }
+
+    fn kv_chain(&self) -> BTreeMap<char, String> {
+        self.iter()
+            .map(|kv| (kv.0, kv.1.into_owned()))
+            .collect::<BTreeMap<_, _>>()
+    }
+
+    /// Keys have to match the `\w` the parser reads them with, or they would
+    /// be written but never read back.
+    fn validate_key(&self, key: char) -> Result<(), DocumentError> {
+        if !is_word_char(key) {
+            return Err(DocumentError::InvalidKey(key));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "multichar-keys")]
+    fn validate_str_key(&self, key: &str) -> Result<(), DocumentError> {
+        if key.chars().count() > MAX_STR_KEY_LEN || !str_key_regex().is_match(key) {
+            return Err(DocumentError::InvalidStrKey(key.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    fn validate_value(&self, key: char, value: &str) -> Result<(), DocumentError> {
+        if value.ends_with(char::is_whitespace) {
+            return Err(DocumentError::InvalidValue(value.to_owned()));
+        }
+
+        match &self.validator {
+            Some(validator) => validator(key, value).map_err(DocumentError::ValidationFailed),
+            None => Ok(()),
+        }
+    }
+
+    async fn namespace_entries(&self, namespace: char) -> BTreeMap<char, String> {
+        self.fetch_multi(namespace)
+            .await
+            .into_iter()
+            .filter_map(|item| {
+                let mut chars = item.chars();
+                Some((chars.next()?, chars.as_str().to_owned()))
+            })
+            .collect()
+    }
+
+    async fn set_namespace_entries(
+        &mut self,
+        namespace: char,
+        entries: BTreeMap<char, String>,
+    ) -> Result<(), DocumentError> {
+        if entries.is_empty() {
+            return self.delete::<String>(namespace, None).await;
+        }
+
+        let items = entries
+            .into_iter()
+            .map(|(key, value)| format!("{}{}", key, value))
+            .collect::<Vec<_>>();
+        self.insert_multi(namespace, items).await
+    }
+
+    /// Expiry timestamps of the entries written with a TTL, expired or not.
+    fn expiries(&self) -> BTreeMap<char, i64> {
+        self.regex
+            .captures_iter(&self.name)
+            .filter_map(|captures| {
+                let key = char_key(captures.get(1)?.as_str())?;
+                let expiry = i64::from_str_radix(captures.get(3)?.as_str(), 36).ok()?;
+                Some((self.normalize_key(key), expiry))
+            })
+            .collect()
+    }
+
+    /// Entries written with a multi-character key, see
+    /// [`Document::insert_str`]. Always empty without `multichar-keys`.
+    fn str_kv(&self) -> BTreeMap<String, String> {
+        let now = Utc::now().timestamp();
+        let prefix = self.prefix.to_string();
+        self.regex
+            .captures_iter(&self.name)
+            .filter_map(|captures| {
+                let key = captures.get(1)?.as_str().trim_end_matches(':');
+                if char_key(key).is_some() {
+                    return None;
+                }
+                if let Some(expiry) = captures.get(3) {
+                    match i64::from_str_radix(expiry.as_str(), 36) {
+                        Ok(expiry) if expiry > now => {}
+                        _ => return None,
+                    }
+                }
+
+                let value = captures.get(2).map_or("", |value| value.as_str());
+                let value = value.replace(&escape_prefix(self.prefix), &prefix);
+                Some((self.normalize_str_key(key), value))
+            })
+            .collect()
+    }
+
+    fn normalize_str_key(&self, key: &str) -> String {
+        if self.case_insensitive {
+            key.to_lowercase()
+        } else {
+            key.to_owned()
+        }
+    }
+
+    fn push_kv(&mut self, kv: BTreeMap<char, String>) -> Result<(), DocumentError> {
+        self.push_named_kv(self.base_name(), kv, self.expiries())
+    }
+
+    fn push_named_kv(
+        &mut self,
+        base_name: String,
+        kv: BTreeMap<char, String>,
+        expiries: BTreeMap<char, i64>,
+    ) -> Result<(), DocumentError> {
+        let str_kv = self.str_kv();
+        self.push_entries(base_name, kv, expiries, str_kv)
+    }
+
+    fn push_entries(
+        &mut self,
+        base_name: String,
+        kv: BTreeMap<char, String>,
+        expiries: BTreeMap<char, i64>,
+        str_kv: BTreeMap<String, String>,
+    ) -> Result<(), DocumentError> {
+        if let Some(max_entries) = self.max_entries {
+            let entries = kv.len() + str_kv.len();
+            if entries > max_entries && entries > self.iter().count() + self.str_kv().len() {
+                return Err(DocumentError::TooManyEntries(max_entries));
+            }
+        }
+
+        let kv_string = kv
+            .iter()
+            .map(|kv| self.encode_entry(&kv.0.to_string(), kv.1, expiries.get(kv.0)))
+            .chain(str_kv.iter().map(|kv| self.encode_entry(kv.0, kv.1, None)))
+            .collect::<String>();
+
+        let mut name_new = base_name;
+        if !kv_string.is_empty() {
+            name_new = name_new + " " + &kv_string;
+        }
+
+        let length = name_new.chars().count();
+        if length > MAX_NICKNAME_LEN {
+            return Err(DocumentError::NicknameTooLong {
+                length,
+                excess: length - MAX_NICKNAME_LEN,
+            });
+        }
+
+        self.set_name(name_new);
+        Ok(())
+    }
+
+    fn encode_entry(&self, key: &str, value: &str, expiry: Option<&i64>) -> String {
+        let mut encoded = self.prefix.to_string() + key;
+        #[cfg(feature = "multichar-keys")]
+        if char_key(key).is_none() || key_terminator_regex().is_match(value) {
+            encoded.push(':');
+        }
+
+        encoded += &value.replace(self.prefix, &escape_prefix(self.prefix));
+        if let Some(expiry) = expiry {
+            encoded = encoded + &self.prefix.to_string() + " " + &to_base36(*expiry as u64);
+        }
+
+        encoded
+    }
+
+    fn restore(&mut self, snapshot: DocumentSnapshot) {
+        self.name = snapshot.name;
+        self.prefix = snapshot.prefix;
+        self.regex = snapshot.regex;
+        #[cfg(feature = "history")]
+        {
+            self.history.entries = snapshot.history;
+            self.history.truncate();
+        }
+    }
+
+    fn set_name(&mut self, name: String) {
+        #[cfg(feature = "history")]
+        let (kv_chain, expiries) = (self.kv_chain(), self.expiries());
+
+        self.name = name;
+
+        #[cfg(feature = "history")]
+        self.history
+            .record(&kv_chain, &expiries, &self.kv_chain(), &self.expiries());
+    }
+}
+
+/// The clone starts from a snapshot of the current access times but tracks
+/// access on its own afterwards.
+impl Clone for Document {
+    fn clone(&self) -> Self {
+        let snapshot = |time: &RwLock<DateTime<Utc>>| {
+            let time = time
+                .try_read()
+                .map(|time| *time)
+                .unwrap_or_else(|_| Utc::now());
+            Arc::new(RwLock::new(time))
+        };
+
+        Self {
+            name: self.name.clone(),
+            prefix: self.prefix,
+            regex: self.regex.clone(),
+            case_insensitive: self.case_insensitive,
+            validator: self.validator.clone(),
+            max_entries: self.max_entries,
+            last_read: snapshot(&self.last_read),
+            last_written: snapshot(&self.last_written),
+            #[cfg(feature = "metrics")]
+            counters: self.counters.snapshot().into(),
+            #[cfg(feature = "history")]
+            history: self.history.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Document")
+            .field("name", &self.name)
+            .field("prefix", &self.prefix)
+            .field("regex", &self.regex)
+            .field("case_insensitive", &self.case_insensitive)
+            .field("validator", &self.validator.as_ref().map(|_| ".."))
+            .field("max_entries", &self.max_entries)
+            .field("last_read", &self.last_read)
+            .field("last_written", &self.last_written)
+            .finish()
+    }
+}
+
+/// Shows the base name followed by the decoded pairs, e.g. `menfie [A=FOO, b=BAR]`.
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.base_name())?;
+
+        let kv_chain = self
+            .kv_chain()
+            .iter()
+            .map(|kv| format!("{}={}", kv.0, kv.1))
+            .chain(self.str_kv().iter().map(|kv| format!("{}={}", kv.0, kv.1)))
+            .collect::<Vec<_>>();
+        if kv_chain.is_empty() {
+            return Ok(());
+        }
+
+        write!(f, " [{}]", kv_chain.join(", "))
+    }
+}
+
+/// Same as [`Document::try_from_nickname`].
+impl TryFrom<(&str, char)> for Document {
+    type Error = DocumentError;
+
+    fn try_from((name, prefix): (&str, char)) -> Result<Self, Self::Error> {
+        Self::try_from_nickname(name.to_owned(), prefix)
+    }
+}
+
+impl From<Document> for HashMap<char, String> {
+    fn from(document: Document) -> Self {
+        document.entries()
+    }
+}
+
+/// Documents are equal when their name and prefix are; the access times are
+/// bookkeeping and do not take part.
+impl PartialEq for Document {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.prefix == other.prefix
+            && self.case_insensitive == other.case_insensitive
+    }
+}
+
+impl Eq for Document {}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Document")]
+struct SerdeDocument<'a> {
+    #[serde(borrow)]
+    name: std::borrow::Cow<'a, str>,
+    prefix: char,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    case_insensitive: bool,
+}
+
+/// Only `name`, `prefix` and the case-insensitive flag are serialized; a
+/// deserialized document starts with fresh access times.
+#[cfg(feature = "serde")]
+impl Serialize for Document {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerdeDocument {
+            name: self.name.as_str().into(),
+            prefix: self.prefix,
+            case_insensitive: self.case_insensitive,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Document {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let document = SerdeDocument::deserialize(deserializer)?;
+
+        Ok(Document {
+            case_insensitive: document.case_insensitive,
+            ..Document::try_new(document.name.into_owned(), document.prefix)
+                .map_err(serde::de::Error::custom)?
+        })
+    }
+}
+
+/// A view into a single key of a [`Document`]. Nothing is written until one of
+/// the `or_insert*` methods is awaited.
+#[must_use = "an entry does nothing until `or_insert` or `or_insert_with` is awaited"]
+pub struct Entry<'a> {
+    document: &'a mut Document,
+    key: char,
+    modify: Option<Box<dyn FnOnce(&mut String) + Send + 'a>>,
+}
+
+impl<'a> Entry<'a> {
+    pub fn key(&self) -> char {
+        self.key
+    }
+
+    pub fn and_modify<F: FnOnce(&mut String) + Send + 'a>(mut self, f: F) -> Self {
+        let modify: Box<dyn FnOnce(&mut String) + Send + 'a> = match self.modify.take() {
+            Some(previous) => Box::new(move |value: &mut String| {
+                previous(value);
+                f(value);
+            }),
+            None => Box::new(f),
+        };
+        self.modify = Some(modify);
+
+        self
+    }
+
+    pub async fn or_insert<T: Into<String>>(self, default: T) -> Result<String, DocumentError> {
+        self.or_insert_with(|| default).await
+    }
+
+    pub async fn or_insert_with<T: Into<String>, F: FnOnce() -> T>(
+        self,
+        default: F,
+    ) -> Result<String, DocumentError> {
+        let value = match self.document.fetch(self.key).await {
+            Some(value) => {
+                let mut value = value.into_owned();
+                match self.modify {
+                    Some(modify) => modify(&mut value),
+                    None => return Ok(value),
+                }
+
+                value
+            }
+            None => default().into(),
+        };
+
+        self.document.insert(self.key, value.clone()).await?;
+        Ok(value)
+    }
+}
+
+/// The state of a [`Document`] at the time of [`Document::snapshot`]. With
+/// the `history` feature it includes the recorded changes, which are put back
+/// along with the pairs.
+#[derive(Debug, Clone)]
+pub struct DocumentSnapshot {
+    name: String,
+    prefix: char,
+    regex: Regex,
+    #[cfg(feature = "history")]
+    history: VecDeque<HistoryEntry>,
+}
+
+/// Gives access to a [`Document`] and restores its earlier state on drop,
+/// unless [`TransactionGuard::commit`] was called. Restoring on drop does not
+/// count as a write for [`Document::since_last_write`].
+#[must_use = "a transaction is rolled back as soon as the guard is dropped"]
+pub struct TransactionGuard<'a> {
+    document: &'a mut Document,
+    snapshot: Option<DocumentSnapshot>,
+}
+
+impl TransactionGuard<'_> {
+    /// Keeps every change made through the guard.
+    pub fn commit(mut self) {
+        self.snapshot = None;
+    }
+}
+
+impl Deref for TransactionGuard<'_> {
+    type Target = Document;
+
+    fn deref(&self) -> &Document {
+        self.document
+    }
+}
+
+impl DerefMut for TransactionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Document {
+        self.document
+    }
+}
+
+impl Drop for TransactionGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            self.document.restore(snapshot);
+        }
+    }
+}
+
+/// The keys of a single namespace of a [`Document`], see
+/// [`Document::insert_ns`].
+pub struct NamespaceView<'a> {
+    document: &'a mut Document,
+    namespace: char,
+}
+
+impl NamespaceView<'_> {
+    pub fn namespace(&self) -> char {
+        self.namespace
+    }
+
+    pub async fn insert<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        self.document.insert_ns(self.namespace, key, value).await
+    }
+
+    pub async fn fetch(&self, key: char) -> Option<String> {
+        self.document.fetch_ns(self.namespace, key).await
+    }
+
+    pub async fn delete(&mut self, key: char) -> Result<(), DocumentError> {
+        self.document.delete_ns(self.namespace, key).await
+    }
+}
+
+/// A [`Document`] whose async methods fail with [`DocumentError::TimedOut`]
+/// instead of waiting forever, see [`Document::with_timeout`].
+#[derive(Debug, Clone)]
+pub struct TimeoutDocument {
+    document: Document,
+    timeout: std::time::Duration,
+}
+
+impl TimeoutDocument {
+    pub fn document(&self) -> &Document {
+        &self.document
+    }
+
+    pub fn document_mut(&mut self) -> &mut Document {
+        &mut self.document
+    }
+
+    pub fn into_inner(self) -> Document {
+        self.document
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout
+    }
+
+    pub async fn insert<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.insert(key, value)).await?
+    }
+
+    pub async fn insert_with_ttl<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+        ttl: Duration,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.insert_with_ttl(key, value, ttl)).await?
+    }
+
+    pub async fn insert_if_absent<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<bool, DocumentError> {
+        timeout(self.timeout, self.document.insert_if_absent(key, value)).await?
+    }
+
+    pub async fn try_insert<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.try_insert(key, value)).await?
+    }
+
+    pub async fn update<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<bool, DocumentError> {
+        timeout(self.timeout, self.document.update(key, value)).await?
+    }
+
+    pub async fn replace<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: T,
+    ) -> Result<Option<String>, DocumentError> {
+        timeout(self.timeout, self.document.replace(key, value)).await?
+    }
+
+    pub async fn update_with<F: FnOnce(Option<&str>) -> Option<String>>(
+        &mut self,
+        key: char,
+        f: F,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.update_with(key, f)).await?
+    }
+
+    pub async fn insert_many<T: Into<String>>(
+        &mut self,
+        pairs: impl IntoIterator<Item = (char, T)>,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.insert_many(pairs)).await?
+    }
+
+    pub async fn extend<I: IntoIterator<Item = (char, T)>, T: Into<String>>(
+        &mut self,
+        iter: I,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.extend(iter)).await?
+    }
+
+    pub async fn merge(
+        &mut self,
+        other: &Document,
+        on_conflict: ConflictStrategy,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.merge(other, on_conflict)).await?
+    }
+
+    pub async fn merge_keep(&mut self, other: &Document) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.merge_keep(other)).await?
+    }
+
+    pub async fn insert_multi<T: Into<String>>(
+        &mut self,
+        key: char,
+        values: Vec<T>,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.insert_multi(key, values)).await?
+    }
+
+    pub async fn delete_multi(&mut self, key: char, value: &str) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.delete_multi(key, value)).await?
+    }
+
+    pub async fn delete<T: Into<String>>(
+        &mut self,
+        key: char,
+        value: Option<T>,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.delete(key, value)).await?
+    }
+
+    pub async fn retain<F: FnMut(char, &str) -> bool>(
+        &mut self,
+        f: F,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.retain(f)).await?
+    }
+
+    pub async fn clear(&mut self) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.clear()).await
+    }
+
+    #[cfg(feature = "history")]
+    pub async fn rollback(&mut self, n: usize) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.rollback(n)).await?
+    }
+
+    pub async fn fetch(&self, key: char) -> Result<Option<Cow<'_, str>>, DocumentError> {
+        timeout(self.timeout, self.document.fetch(key)).await
+    }
+
+    pub async fn fetch_or_default<'a>(
+        &'a self,
+        key: char,
+        default: &'a str,
+    ) -> Result<Cow<'a, str>, DocumentError> {
+        timeout(self.timeout, self.document.fetch_or_default(key, default)).await
+    }
+
+    pub async fn fetch_parsed<T: FromStr>(&self, key: char) -> Result<Option<T>, DocumentError> {
+        timeout(self.timeout, self.document.fetch_parsed(key)).await
+    }
+
+    pub async fn fetch_or_insert<T: Into<String>>(
+        &mut self,
+        key: char,
+        default: T,
+    ) -> Result<Cow<'_, str>, DocumentError> {
+        timeout(self.timeout, self.document.fetch_or_insert(key, default)).await?
+    }
+
+    pub async fn fetch_multi(&self, key: char) -> Result<Vec<String>, DocumentError> {
+        timeout(self.timeout, self.document.fetch_multi(key)).await
+    }
+
+    pub async fn insert_ns<T: Into<String>>(
+        &mut self,
+        namespace: char,
+        key: char,
+        value: T,
+    ) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.insert_ns(namespace, key, value)).await?
+    }
+
+    pub async fn fetch_ns(
+        &self,
+        namespace: char,
+        key: char,
+    ) -> Result<Option<String>, DocumentError> {
+        timeout(self.timeout, self.document.fetch_ns(namespace, key)).await
+    }
+
+    pub async fn delete_ns(&mut self, namespace: char, key: char) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.delete_ns(namespace, key)).await?
+    }
+
+    pub async fn fetch_all(&self) -> Result<HashMap<char, String>, DocumentError> {
+        timeout(self.timeout, self.document.fetch_all()).await
+    }
+
+    pub async fn touch(&self) -> Result<(), DocumentError> {
+        timeout(self.timeout, self.document.touch()).await
+    }
+
+    pub async fn last_accessed(&self) -> Result<DateTime<Utc>, DocumentError> {
+        timeout(self.timeout, self.document.last_accessed()).await
+    }
+
+    pub async fn since_last_access(&self) -> Result<Duration, DocumentError> {
+        timeout(self.timeout, self.document.since_last_access()).await
+    }
+
+    pub async fn is_stale(&self, threshold: Duration) -> Result<bool, DocumentError> {
+        timeout(self.timeout, self.document.is_stale(threshold)).await
+    }
+}
+
+async fn timeout<F: Future>(
+    duration: std::time::Duration,
+    future: F,
+) -> Result<F::Output, DocumentError> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| DocumentError::TimedOut)
+}
+
+impl<'a> IntoIterator for &'a Document {
+    type Item = (char, Cow<'a, str>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct Iter<'a> {
+    prefix: char,
+    case_insensitive: bool,
+    now: i64,
+    captures: CaptureMatches<'a, 'a>,
+}
+
+/// Yields each key with its unescaped value, borrowing from the name unless
+/// the value contains the prefix. Expired entries and entries with a
+/// multi-character key are skipped.
+impl<'a> Iterator for Iter<'a> {
+    type Item = (char, Cow<'a, str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, captures) = loop {
+            let captures = self.captures.next()?;
+            let key = match char_key(captures.get(1)?.as_str()) {
+                Some(key) => key,
+                None => continue,
+            };
+            match captures.get(3) {
+                Some(expiry) => match i64::from_str_radix(expiry.as_str(), 36) {
+                    Ok(expiry) if expiry > self.now => break (key, captures),
+                    _ => continue,
+                },
+                None => break (key, captures),
+            }
+        };
+
+        let value = captures.get(2).map_or("", |value| value.as_str());
+        let value = if value.contains(self.prefix) {
+            let prefix = self.prefix.to_string();
+            Cow::Owned(value.replace(&escape_prefix(self.prefix), &prefix))
+        } else {
+            Cow::Borrowed(value)
+        };
+
+        if self.case_insensitive {
+            Some((fold_key(key), value))
+        } else {
+            Some((key, value))
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+
+    static_assertions::assert_impl_all!(Document: Send, Sync);
+
+    #[tokio::test]
+    async fn from_nickname() {
+        let mut inserted = Document::new("menfie".to_owned(), '^');
+        inserted
+            .insert_many(vec![('A', "FOO"), ('b', "BAR")])
+            .await
+            .unwrap();
+
+        let parsed = Document::from_nickname("menfie ^AFOO^bBAR".to_owned(), '^');
+        assert_eq!(parsed, inserted);
+        assert_eq!(parsed.fetch('b').await.as_deref(), Some("BAR"));
+        assert_eq!(
+            Document::try_from_nickname("menfie ^AFOO^bBAR".to_owned(), '^'),
+            Ok(inserted)
+        );
+
+        for name in &["menfie ^ ^AFOO", "menfie ^AFOO^", "menfie ^AFOO^ABAR"] {
+            assert_eq!(
+                Document::try_from_nickname(name.to_string(), '^'),
+                Err(DocumentError::Malformed(name.to_string()))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn try_from() {
+        let document = Document::try_from(("menfie", '^')).unwrap();
+        assert!(document.is_empty());
+
+        let document = Document::try_from(("menfie ^AFOO^bBAR", '^')).unwrap();
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+        assert_eq!(document.fetch('b').await.as_deref(), Some("BAR"));
+
+        assert_eq!(
+            Document::try_from(("menfie ^", '^')),
+            Err(DocumentError::Malformed("menfie ^".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn insert() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+    }
+
+    #[tokio::test]
+    async fn insert_sorted() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('z', "3").await.unwrap();
+        document.insert('m', "2").await.unwrap();
+        document.insert('a', "1").await.unwrap();
+        assert_eq!(document.name, "menfie ^a1^m2^z3");
+    }
+
+    #[tokio::test]
+    async fn delete() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        document.delete::<String>('A', None).await.unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        document.delete('A', Some("FOO")).await.unwrap();
+        assert_eq!(document.name, "menfie ^bBAR");
+    }
+
+    #[tokio::test]
+    async fn fetch() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+    }
+
+    #[tokio::test]
+    async fn insert_if_absent() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.insert_if_absent('A', "FOO").await, Ok(true));
+        assert_eq!(document.insert_if_absent('A', "BAR").await, Ok(false));
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+    }
+
+    #[tokio::test]
+    async fn try_insert() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.try_insert('A', "FOO").await, Ok(()));
+        assert_eq!(
+            document.try_insert('A', "BAR").await,
+            Err(DocumentError::KeyExists {
+                key: 'A',
+                value: "FOO".to_owned()
+            })
+        );
+        assert_eq!(document.name, "menfie ^AFOO");
+        assert_eq!(
+            document.try_insert('b', "x".repeat(MAX_NICKNAME_LEN)).await,
+            Err(DocumentError::NicknameTooLong {
+                length: MAX_NICKNAME_LEN + 14,
+                excess: 14
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn extend() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .extend(vec![('b', "BAR"), ('A', "FOO")])
+            .await
+            .unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+
+        assert_eq!(
+            document.extend(vec![('c', "BAZ"), (' ', "QUX")]).await,
+            Err(DocumentError::InvalidKey(' '))
+        );
+        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+    }
+
+    #[tokio::test]
+    async fn update() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.update('A', "FOO").await, Ok(false));
+        assert_eq!(document.name, "menfie");
+
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.update('A', "BAR").await, Ok(true));
+        assert_eq!(document.fetch('A').await.as_deref(), Some("BAR"));
+    }
+
+    #[tokio::test]
+    async fn replace() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.replace('r', "bronze").await, Ok(None));
+        assert_eq!(
+            document.replace('r', "silver").await,
+            Ok(Some("bronze".to_owned()))
+        );
+        assert_eq!(document.fetch('r').await.as_deref(), Some("silver"));
+    }
+
+    #[tokio::test]
+    async fn update_with() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .update_with('c', |value| {
+                assert_eq!(value, None);
+                Some("1".to_owned())
+            })
+            .await
+            .unwrap();
+        assert_eq!(document.fetch('c').await.as_deref(), Some("1"));
+
+        document
+            .update_with('c', |value| value.map(|value| value.to_owned() + "0"))
+            .await
+            .unwrap();
+        assert_eq!(document.fetch('c').await.as_deref(), Some("10"));
+
+        document.update_with('c', |_| None).await.unwrap();
+        assert_eq!(document.name, "menfie");
+    }
+
+    #[tokio::test]
+    async fn insert_many() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_many(vec![('b', "BAR"), ('A', "FOO")])
+            .await
+            .unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+
+        let long = "x".repeat(20);
+        assert_eq!(
+            document
+                .insert_many(vec![('c', "BAZ"), ('d', long.as_str())])
+                .await,
+            Err(DocumentError::NicknameTooLong {
+                length: 44,
+                excess: 12
+            })
+        );
+        assert_eq!(
+            document.insert_many(vec![('c', "BAZ"), ('^', "QUX")]).await,
+            Err(DocumentError::InvalidKey('^'))
+        );
+        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+    }
+
+    #[tokio::test]
+    async fn insert_many_matches_sequential_inserts() {
+        let pairs = vec![('e', "5"), ('a', "1"), ('d', "4"), ('b', "2"), ('c', "3")];
+
+        let mut sequential = Document::new("menfie".to_owned(), '^');
+        for (key, value) in pairs.clone() {
+            sequential.insert(key, value).await.unwrap();
+        }
+
+        let mut batch = Document::new("menfie".to_owned(), '^');
+        batch.insert_many(pairs).await.unwrap();
+        assert_eq!(batch, sequential);
+    }
+
+    #[tokio::test]
+    async fn merge() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_many(vec![('A', "FOO"), ('b', "BAR")])
+            .await
+            .unwrap();
+        let mut other = Document::new("bob".to_owned(), '~');
+        other
+            .insert_many(vec![('A', "FOO"), ('b', "QUX"), ('c', "BAZ")])
+            .await
+            .unwrap();
+
+        let mut merged = document.clone();
+        assert_eq!(
+            merged.merge(&other, ConflictStrategy::FailOnConflict).await,
+            Err(DocumentError::KeyConflict('b'))
+        );
+        assert_eq!(merged.name, "menfie ^AFOO^bBAR");
+
+        merged
+            .merge(&other, ConflictStrategy::KeepExisting)
+            .await
+            .unwrap();
+        assert_eq!(merged.name, "menfie ^AFOO^bBAR^cBAZ");
+
+        let mut merged = document.clone();
+        merged
+            .merge(&other, ConflictStrategy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(merged.name, "menfie ^AFOO^bQUX^cBAZ");
+
+        let mut long = Document::new("bob".to_owned(), '^');
+        long.insert('d', "x".repeat(20)).await.unwrap();
+        assert!(merged
+            .merge(&long, ConflictStrategy::Overwrite)
+            .await
+            .is_err());
+        assert_eq!(merged.name, "menfie ^AFOO^bQUX^cBAZ");
+    }
+
+    #[tokio::test]
+    async fn merge_keep() {
+        let mut defaults = Document::new("menfie".to_owned(), '^');
+        defaults
+            .insert_many(vec![('l', "1"), ('r', "guest")])
+            .await
+            .unwrap();
+        let mut overrides = Document::new("bob".to_owned(), '^');
+        overrides.insert('r', "admin").await.unwrap();
+
+        let mut merged = defaults.clone();
+        merged.merge_keep(&overrides).await.unwrap();
+        assert_eq!(merged.name, "menfie ^l1^rguest");
+
+        let mut disjoint = Document::new("bob".to_owned(), '^');
+        disjoint.insert('x', "42").await.unwrap();
+        merged.merge_keep(&disjoint).await.unwrap();
+        assert_eq!(merged.name, "menfie ^l1^rguest^x42");
+
+        let mut merged = defaults.clone();
+        merged
+            .merge(&disjoint, ConflictStrategy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(merged.name, "menfie ^l1^rguest^x42");
+    }
+
+    #[tokio::test]
+    async fn whitespace_value() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('l', "New York").await.unwrap();
+        document.insert('m', "FOO").await.unwrap();
+        document.insert('A', "B A R").await.unwrap();
+        assert_eq!(document.name, "menfie ^AB A R^lNew York^mFOO");
+        assert_eq!(document.fetch('l').await.as_deref(), Some("New York"));
+        assert_eq!(document.fetch('m').await.as_deref(), Some("FOO"));
+        assert_eq!(document.fetch('A').await.as_deref(), Some("B A R"));
+    }
+
+    #[tokio::test]
+    async fn empty_value() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('x', "").await.unwrap();
+        document.insert('y', "FOO").await.unwrap();
+        assert_eq!(document.name, "menfie ^x^yFOO");
+        assert!(document.exists('x'));
+        assert_eq!(document.fetch('x').await.as_deref(), Some(""));
+        assert_eq!(document.fetch('y').await.as_deref(), Some("FOO"));
+    }
+
+    #[tokio::test]
+    async fn fetch_or() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.fetch_or_default('r', "guest").await, "guest");
+        assert_eq!(
+            document.fetch_or_insert('r', "admin").await.as_deref(),
+            Ok("admin")
+        );
+        assert_eq!(
+            document.fetch_or_insert('r', "guest").await.as_deref(),
+            Ok("admin")
+        );
+        assert_eq!(document.fetch_or_default('r', "guest").await, "admin");
+
+        let long = "x".repeat(MAX_NICKNAME_LEN);
+        assert!(document.fetch_or_insert('l', long).await.is_err());
+        assert!(!document.exists('l'));
+    }
+
+    #[tokio::test]
+    async fn multi() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_multi('r', vec!["12", "3|4", "5\\"])
+            .await
+            .unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.fetch_multi('r').await, vec!["12", "3|4", "5\\"]);
+        assert_eq!(document.fetch_multi('A').await, vec!["FOO"]);
+        assert!(document.fetch_multi('x').await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn delete_multi() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_multi('r', vec!["1", "2", "1"])
+            .await
+            .unwrap();
+        document.delete_multi('r', "1").await.unwrap();
+        assert_eq!(document.fetch_multi('r').await, vec!["2", "1"]);
+        document.delete_multi('r', "3").await.unwrap();
+        assert_eq!(document.name, "menfie ^r2|1");
+
+        document.delete_multi('r', "2").await.unwrap();
+        document.delete_multi('r', "1").await.unwrap();
+        assert!(!document.exists('r'));
+        assert_eq!(
+            document.delete_multi('r', "1").await,
+            Err(DocumentError::KeyNotFound('r'))
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        let snapshot = document.snapshot();
+
+        document.insert('b', "BAR").await.unwrap();
+        document.set_base_name("bob").unwrap();
+        document.migrate_prefix('~').unwrap();
+        document.restore_snapshot(snapshot).await;
+        assert_eq!(document.name, "menfie ^AFOO");
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+        assert_eq!(document.fetch('b').await, None);
+    }
+
+    #[tokio::test]
+    async fn transaction() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        let mut transaction = document.transaction();
+        transaction.insert('A', "FOO").await.unwrap();
+        transaction.insert('b', "BAR").await.unwrap();
+        assert!(transaction.insert('c', "x".repeat(32)).await.is_err());
+        drop(transaction);
+        assert_eq!(document.name, "menfie");
+
+        let mut transaction = document.transaction();
+        transaction.insert('A', "FOO").await.unwrap();
+        transaction.commit();
+        assert_eq!(document.name, "menfie ^AFOO");
+    }
+
+    #[tokio::test]
+    async fn namespaces() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        document.insert_ns('g', 'A', "1").await.unwrap();
+        document.insert_ns('g', 'b', "2|3").await.unwrap();
+        document.namespace('m').insert('A', "4").await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^gA1|b2\\|3^mA4");
+
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+        assert_eq!(document.fetch_ns('g', 'A').await.as_deref(), Some("1"));
+        assert_eq!(document.fetch_ns('g', 'b').await.as_deref(), Some("2|3"));
+        assert_eq!(
+            document.namespace('m').fetch('A').await.as_deref(),
+            Some("4")
+        );
+        assert_eq!(document.fetch_ns('m', 'b').await, None);
+
+        document.delete_ns('g', 'A').await.unwrap();
+        document.namespace('m').delete('A').await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^gb2\\|3");
+        assert_eq!(
+            document.delete_ns('m', 'A').await,
+            Err(DocumentError::KeyNotFound('A'))
+        );
+    }
+
+    #[tokio::test]
+    async fn entry() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(
+            document.entry('r').or_insert("guest").await.unwrap(),
+            "guest"
+        );
+        assert_eq!(
+            document.entry('r').or_insert("admin").await.unwrap(),
+            "guest"
+        );
+        assert_eq!(
+            document
+                .entry('r')
+                .and_modify(|value| value.push('s'))
+                .and_modify(|value| value.push('!'))
+                .or_insert("admin")
+                .await
+                .unwrap(),
+            "guests!"
+        );
+        assert_eq!(
+            document
+                .entry('c')
+                .and_modify(|value| value.push('1'))
+                .or_insert_with(|| "0")
+                .await
+                .unwrap(),
+            "0"
+        );
+        assert_eq!(document.name, "menfie ^c0^rguests!");
+    }
+
+    #[tokio::test]
+    async fn ttl() {
+        let mut document = Document::new("bob".to_owned(), '^');
+        document
+            .insert_with_ttl('s', "busy", Duration::hours(1))
+            .await
+            .unwrap();
+        document
+            .insert_with_ttl('a', "x", Duration::seconds(-1))
+            .await
+            .unwrap();
+
+        assert_eq!(document.fetch('s').await.as_deref(), Some("busy"));
+        assert!(document.exists('s'));
+        assert_eq!(document.fetch('a').await, None);
+        assert!(!document.exists('a'));
+        assert_eq!(document.keys().collect::<Vec<_>>(), vec!['s']);
+        assert_eq!(document.base_name(), "bob");
+
+        assert!(document.name.contains("^ax"));
+        document.purge_expired().unwrap();
+        assert!(!document.name.contains("^ax"));
+        let (encoded, expiry) = document.name.rsplit_once("^ ").unwrap();
+        assert_eq!(encoded, "bob ^sbusy");
+        let expiry = i64::from_str_radix(expiry, 36).unwrap();
+        let expected = (Utc::now() + Duration::hours(1)).timestamp();
+        assert!((expected - expiry).abs() <= 2);
+
+        document.insert('l', "43").await.unwrap();
+        assert!(document.name.starts_with("bob ^l43^sbusy^ "));
+        document.insert('s', "free").await.unwrap();
+        assert_eq!(document.name, "bob ^l43^sfree");
+
+        assert_eq!(
+            document
+                .insert_with_ttl('s', "busy", Duration::max_value())
+                .await,
+            Err(DocumentError::TtlOutOfRange(Duration::max_value()))
+        );
+        assert_eq!(document.name, "bob ^l43^sfree");
+    }
+
+    #[tokio::test]
+    async fn ttl_overwrite() {
+        let mut document = Document::new("bob".to_owned(), '^');
+        let mut other = Document::new("alice".to_owned(), '^');
+        other.insert('m', "y").await.unwrap();
+        let hour = Duration::hours(1);
+
+        document.insert_with_ttl('u', "x", hour).await.unwrap();
+        document.update('u', "y").await.unwrap();
+        document.insert_with_ttl('w', "x", hour).await.unwrap();
+        document
+            .update_with('w', |_| Some("y".to_owned()))
+            .await
+            .unwrap();
+        document.insert_with_ttl('m', "x", hour).await.unwrap();
+        document
+            .merge(&other, ConflictStrategy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(document.name, "bob ^my^uy^wy");
+        assert_eq!(document.expiries(), BTreeMap::new());
+    }
+
+    #[test]
+    fn base36() {
+        assert_eq!(to_base36(0), "0");
+        assert_eq!(to_base36(35), "z");
+        assert_eq!(to_base36(36), "10");
+        assert_eq!(to_base36(1_800_000_000), "tro8w0");
+    }
+
+    #[tokio::test]
+    async fn validator() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.set_validator(|key, value| {
+            let is_snowflake =
+                (17..=19).contains(&value.len()) && value.chars().all(|c| c.is_ascii_digit());
+            if key == 'g' && !is_snowflake {
+                return Err(format!("{:?} is not a snowflake", value));
+            }
+
+            Ok(())
+        });
+
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('g', "12345678901234567").await.unwrap();
+        assert_eq!(
+            document.insert('g', "FOO").await,
+            Err(DocumentError::ValidationFailed(
+                "\"FOO\" is not a snowflake".to_owned()
+            ))
+        );
+        assert_eq!(
+            document.insert_many(vec![('b', "BAR"), ('g', "1")]).await,
+            Err(DocumentError::ValidationFailed(
+                "\"1\" is not a snowflake".to_owned()
+            ))
+        );
+        assert_eq!(document.name, "menfie ^AFOO^g12345678901234567");
+
+        let mut clone = document.clone();
+        assert!(clone.insert('g', "FOO").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let mut document = Document::new("menfie".to_owned(), '^')
+            .with_timeout(std::time::Duration::from_millis(25));
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.fetch('A').await.unwrap().as_deref(), Some("FOO"));
+
+        let last_read = document.document().last_read.clone();
+        let last_written = document.document().last_written.clone();
+        let guards = (last_read.write().await, last_written.write().await);
+        assert_eq!(document.fetch('A').await, Err(DocumentError::TimedOut));
+        assert_eq!(
+            document.insert('b', "BAR").await,
+            Err(DocumentError::TimedOut)
+        );
+        drop(guards);
+
+        document.insert('b', "BAR").await.unwrap();
+        assert_eq!(document.into_inner().name, "menfie ^AFOO^bBAR");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn operation_counts() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.operation_counts(), OperationCounts::default());
+
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        document.delete::<String>('A', None).await.unwrap();
+        document.fetch('b').await;
+        document.fetch_all().await;
+        assert_eq!(
+            document.operation_counts(),
+            OperationCounts {
+                insert_count: 2,
+                delete_count: 1,
+                fetch_count: 2,
+            }
+        );
+
+        document
+            .insert_many(vec![('c', "BAZ"), ('d', "QUX")])
+            .await
+            .unwrap();
+        document
+            .update_with('c', |_| Some("X".to_owned()))
+            .await
+            .unwrap();
+        document.update_with('c', |_| None).await.unwrap();
+        document.retain(|key, _| key != 'd').await.unwrap();
+        document.namespace('g').insert('e', "E").await.unwrap();
+        document.clear().await;
+        document.entries();
+        assert_eq!(
+            document.operation_counts(),
+            OperationCounts {
+                insert_count: 5,
+                delete_count: 4,
+                fetch_count: 3,
+            }
+        );
+        assert_eq!(
+            document.clone().operation_counts(),
+            document.operation_counts()
+        );
+    }
+
+    #[cfg(feature = "multichar-keys")]
+    #[tokio::test]
+    async fn str_keys() {
+        let mut document = Document::try_new("menfie".to_owned(), '^').unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        document.insert_str("tz", "UTC").await.unwrap();
+        document.insert_str("b", "BAR").await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^bBAR^tz:UTC");
+        assert_eq!(document.fetch_str("tz").await, Ok(Some("UTC".to_owned())));
+        assert_eq!(document.fetch('b').await.as_deref(), Some("BAR"));
+        assert_eq!(document.exists_str("tz"), Ok(true));
+        assert_eq!(document.len(), 2);
+        assert_eq!(document.to_string(), "menfie [A=FOO, b=BAR, tz=UTC]");
+
+        document.insert('c', "BAZ").await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^bBAR^cBAZ^tz:UTC");
+
+        assert_eq!(document.delete_str("tz").await, Ok(Some("UTC".to_owned())));
+        assert_eq!(document.delete_str("tz").await, Ok(None));
+        assert_eq!(document.name, "menfie ^AFOO^bBAR^cBAZ");
+
+        for key in &["", "t z", "tz:", "^tz"] {
+            let err = DocumentError::InvalidStrKey(key.to_string());
+            assert_eq!(document.insert_str(key, "x").await, Err(err.clone()));
+            assert_eq!(document.fetch_str(key).await, Err(err.clone()));
+            assert_eq!(document.exists_str(key), Err(err.clone()));
+            assert_eq!(document.delete_str(key).await, Err(err));
+        }
+
+        let mut document = Document::new_case_insensitive("menfie".to_owned(), '^').unwrap();
+        document.insert_str("TZ", "UTC").await.unwrap();
+        assert_eq!(document.fetch_str("tz").await, Ok(Some("UTC".to_owned())));
+    }
+
+    #[cfg(feature = "multichar-keys")]
+    #[tokio::test]
+    async fn str_key_terminator() {
+        let mut document = Document::try_new("menfie".to_owned(), '^').unwrap();
+        document.insert('a', "b:c").await.unwrap();
+        document.insert('d', ":e").await.unwrap();
+        document.insert('f', "g h:i").await.unwrap();
+        assert_eq!(document.name, "menfie ^a:b:c^d::e^fg h:i");
+        assert_eq!(document.fetch('a').await.as_deref(), Some("b:c"));
+        assert_eq!(document.fetch('d').await.as_deref(), Some(":e"));
+        assert_eq!(document.fetch('f').await.as_deref(), Some("g h:i"));
+        assert!(document.str_kv().is_empty());
+
+        let parsed = Document::try_from_nickname("menfie ^ab:c".to_owned(), '^').unwrap();
+        assert_eq!(parsed.fetch_str("ab").await, Ok(Some("c".to_owned())));
+        assert_eq!(parsed.fetch('a').await, None);
+
+        assert_eq!(
+            Document::try_new(String::new(), ':').unwrap_err(),
+            DocumentError::InvalidPrefix(':')
+        );
+    }
+
+    #[cfg(feature = "multichar-keys")]
+    #[tokio::test]
+    async fn str_key_length() {
+        let mut document = Document::try_new("menfie".to_owned(), '^').unwrap();
+        document.insert_str("pron", "she").await.unwrap();
+        document.insert_str("ağ", "x").await.unwrap();
+        for key in &["pronoun", "abcde", "ağıll"] {
+            assert_eq!(
+                document.insert_str(key, "x").await,
+                Err(DocumentError::InvalidStrKey(key.to_string()))
+            );
+        }
+        assert_eq!(document.name, "menfie ^ağ:x^pron:she");
+
+        let mut document = Document::try_from_nickname("menfie ^ağğ:x".to_owned(), '^').unwrap();
+        assert_eq!(document.fetch_str("ağğ").await, Ok(Some("x".to_owned())));
+        document.insert_str("ağğ", "y").await.unwrap();
+        document.insert_str("ığğü", "z").await.unwrap();
+        assert_eq!(document.name, "menfie ^ağğ:y^ığğü:z");
+        assert_eq!(document.delete_str("ağğ").await, Ok(Some("y".to_owned())));
+        assert_eq!(document.delete_str("ığğü").await, Ok(Some("z".to_owned())));
+        assert_eq!(document.name, "menfie");
+
+        let mut document = Document::try_new("menfie".to_owned(), '^').unwrap();
+        document.insert('a', "bcd:x").await.unwrap();
+        document.insert('b', "cdef:x").await.unwrap();
+        assert_eq!(document.name, "menfie ^a:bcd:x^bcdef:x");
+        assert_eq!(document.fetch('a').await.as_deref(), Some("bcd:x"));
+        assert_eq!(document.fetch('b').await.as_deref(), Some("cdef:x"));
+        assert!(document.str_kv().is_empty());
+    }
+
+    #[cfg(feature = "history")]
+    #[tokio::test]
+    async fn rollback() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        document.insert('A', "BAZ").await.unwrap();
+        document.insert('A', "x".repeat(32)).await.unwrap_err();
+        document.delete::<String>('b', None).await.unwrap();
+        assert_eq!(document.history_len(), 4);
+
+        document.rollback(2).await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+        assert_eq!(
+            document.rollback(3).await,
+            Err(DocumentError::HistoryExhausted {
+                requested: 3,
+                available: 2
+            })
+        );
+        document.rollback(2).await.unwrap();
+        assert_eq!(document.name, "menfie");
+
+        document.set_max_history_depth(1);
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        assert_eq!(document.history_len(), 1);
+        document.rollback(1).await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO");
+    }
+
+    #[cfg(feature = "history")]
+    #[tokio::test]
+    async fn history_entries() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_with_ttl('s', "busy", Duration::hours(1))
+            .await
+            .unwrap();
+        let busy = document.name.clone();
+        document
+            .insert_many(vec![('A', "FOO"), ('s', "free")])
+            .await
+            .unwrap();
+        assert_eq!(
+            document
+                .history()
+                .map(|entry| (entry.operation, entry.key, entry.old_value.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                (ChangeKind::Insert, 's', None),
+                (ChangeKind::Insert, 'A', None),
+                (ChangeKind::Insert, 's', Some("busy")),
+            ]
+        );
+
+        document.set_base_name("bob").unwrap();
+        assert_eq!(document.history_len(), 3);
+        document.rollback(1).await.unwrap();
+        assert_eq!(
+            document.name,
+            busy.replace("menfie ^sbusy", "bob ^AFOO^sbusy")
+        );
+        assert_eq!(document.history_len(), 2);
+
+        document.migrate_prefix('~').unwrap();
+        {
+            let mut transaction = document.transaction();
+            transaction.delete::<String>('A', None).await.unwrap();
+            assert_eq!(transaction.history_len(), 3);
+        }
+        assert_eq!(document.history_len(), 2);
+
+        document.rollback(2).await.unwrap();
+        assert_eq!(document.name, "bob");
+        assert_eq!(document.history_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn touch() {
+        let document = Document::new("menfie".to_owned(), '^');
+        *document.last_read.write().await = Utc::now() - Duration::hours(1);
+        *document.last_written.write().await = Utc::now() - Duration::hours(1);
+        assert!(document.since_last_access().await >= Duration::hours(1));
+        document.touch().await;
+        assert!(document.since_last_access().await < Duration::seconds(1));
+    }
+
+    #[tokio::test]
+    async fn is_stale() {
+        let document = Document::new("menfie".to_owned(), '^');
+        assert!(!document.is_stale(Duration::minutes(1)).await);
+        *document.last_read.write().await = Utc::now() - Duration::hours(1);
+        *document.last_written.write().await = Utc::now() - Duration::hours(1);
+        assert!(document.is_stale(Duration::minutes(1)).await);
+        assert!(!document.is_stale(Duration::hours(2)).await);
+    }
+
+    #[tokio::test]
+    async fn last_accessed() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        let last_accessed = document.last_accessed().await;
+        assert!(Utc::now() - last_accessed < Duration::seconds(1));
+        assert_eq!(last_accessed, *document.last_written.read().await);
+        assert_eq!(document.last_access_at().await, last_accessed);
+    }
+
+    #[tokio::test]
+    async fn since_last_write() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        *document.last_written.write().await = Utc::now() - Duration::hours(1);
+
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+        document.fetch_all().await;
+        assert!(document.since_last_write().await >= Duration::hours(1));
+        assert!(document.since_last_access().await < Duration::seconds(1));
+
+        document.delete::<String>('A', None).await.unwrap();
+        assert!(document.since_last_write().await < Duration::seconds(1));
+    }
+
+    #[tokio::test]
+    async fn fetch_parsed() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('l', "42").await.unwrap();
+        document.insert('n', "FOO").await.unwrap();
+        assert_eq!(document.fetch_parsed::<i32>('l').await, Some(42));
+        assert_eq!(document.fetch_parsed::<i32>('n').await, None);
+        assert_eq!(document.fetch_parsed::<i32>('x').await, None);
+    }
+
+    #[tokio::test]
+    async fn case_sensitive_by_default() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        assert!(!document.exists('a'));
+        assert_eq!(document.fetch('a').await, None);
+        document.insert('a', "BAR").await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO^aBAR");
+    }
+
+    #[tokio::test]
+    async fn case_insensitive() {
+        let mut document = Document::new_case_insensitive("menfie".to_owned(), '^').unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.name, "menfie ^aFOO");
+        assert!(document.exists('A'));
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+
+        document.insert('a', "BAR").await.unwrap();
+        assert_eq!(document.name, "menfie ^aBAR");
+        document.delete::<String>('A', None).await.unwrap();
+        assert_eq!(document.name, "menfie");
+
+        let document = Document::new_case_insensitive("menfie ^AFOO".to_owned(), '^').unwrap();
+        assert_eq!(document.fetch('a').await.as_deref(), Some("FOO"));
+        assert_eq!(document.keys().collect::<Vec<_>>(), vec!['a']);
+    }
+
+    #[tokio::test]
+    async fn default_prefix() {
+        let mut document = Document::with_default_prefix("x".to_owned());
+        assert!(!document.exists('q'));
+        document.insert('q', "FOO").await.unwrap();
+        assert_eq!(document.name, "x ^qFOO");
+        assert_eq!(document.fetch('q').await.as_deref(), Some("FOO"));
+    }
+
+    #[test]
+    fn invalid_prefix() {
+        for prefix in &[
+            'a', '7', '_', 'ş', '\u{301}', '‿', ' ', '\n', '\u{0}', '\u{1b}', '\u{7f}',
+        ] {
+            assert_eq!(
+                Document::try_new("menfie".to_owned(), *prefix),
+                Err(DocumentError::InvalidPrefix(*prefix))
+            );
+        }
+        for prefix in &['^', '½', '²'] {
+            assert!(Document::try_new("menfie".to_owned(), *prefix).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn metacharacter_prefixes() {
+        for prefix in &['\\', '[', ']', '-', '&', '~', '.', '*', '(', '|', '§'] {
+            let mut document = Document::try_new("menfie".to_owned(), *prefix).unwrap();
+            document.insert('A', "FOO").await.unwrap();
+            document.insert('b', "BAR").await.unwrap();
+            assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+            assert_eq!(document.fetch('b').await.as_deref(), Some("BAR"));
+        }
+    }
+
+    #[tokio::test]
+    async fn insert_errors() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(
+            document.insert('^', "FOO").await,
+            Err(DocumentError::InvalidKey('^'))
+        );
+        assert_eq!(
+            document.insert('A', "FO ").await,
+            Err(DocumentError::InvalidValue("FO ".to_owned()))
+        );
+        assert_eq!(
+            document.insert('A', "a".repeat(32)).await,
+            Err(DocumentError::NicknameTooLong {
+                length: 41,
+                excess: 9
+            })
+        );
+        assert_eq!(document.name, "menfie");
+    }
+
+    #[tokio::test]
+    async fn invalid_keys() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        for key in &['^', ' ', '\n', '\u{0}', '\u{7f}', '-', '!', '~', '½', '²'] {
+            assert_eq!(
+                document.insert(*key, "BAR").await,
+                Err(DocumentError::InvalidKey(*key))
+            );
+            assert_eq!(
+                document.delete::<String>(*key, None).await,
+                Err(DocumentError::InvalidKey(*key))
+            );
+        }
+        assert_eq!(document.name, "menfie ^AFOO");
+    }
+
+    #[tokio::test]
+    async fn word_keys() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        for key in &['A', 'z', '7', '_', 'ş', '\u{301}', '‿'] {
+            document.insert(*key, "X").await.unwrap();
+            assert_eq!(document.fetch(*key).await.as_deref(), Some("X"));
+        }
+        assert_eq!(document.name, "menfie ^7X^AX^_X^zX^şX^\u{301}X^‿X");
+    }
+
+    #[tokio::test]
+    async fn max_entries() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.set_max_entries(Some(2));
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        assert_eq!(
+            document.insert('c', "BAZ").await,
+            Err(DocumentError::TooManyEntries(2))
+        );
+        assert_eq!(
+            document.insert_many(vec![('A', "QUX"), ('c', "BAZ")]).await,
+            Err(DocumentError::TooManyEntries(2))
+        );
+        assert_eq!(document.name, "menfie ^AFOO^bBAR");
+
+        document.insert('A', "QUX").await.unwrap();
+        assert_eq!(document.name, "menfie ^AQUX^bBAR");
+        document.delete::<String>('b', None).await.unwrap();
+        document.insert('c', "BAZ").await.unwrap();
+
+        let mut document = Document::new("menfie ^A1^b2^c3".to_owned(), '^');
+        document.set_max_entries(Some(2));
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(
+            document.insert('d', "4").await,
+            Err(DocumentError::TooManyEntries(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn nickname_limit() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert('A', "x".repeat(MAX_NICKNAME_LEN - 9))
+            .await
+            .unwrap();
+        assert_eq!(document.name.chars().count(), MAX_NICKNAME_LEN);
+
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(
+            document.insert('A', "x".repeat(MAX_NICKNAME_LEN - 8)).await,
+            Err(DocumentError::NicknameTooLong {
+                length: MAX_NICKNAME_LEN + 1,
+                excess: 1
+            })
+        );
+        assert_eq!(document.name, "menfie");
+    }
+
+    #[tokio::test]
+    async fn delete_last() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        document.delete::<String>('A', None).await.unwrap();
+        assert_eq!(document.name, "menfie");
+    }
+
+    #[tokio::test]
+    async fn retain() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_many(vec![('A', "1"), ('b', "2"), ('C', "3"), ('d', "")])
+            .await
+            .unwrap();
+        document.retain(|_, value| !value.is_empty()).await.unwrap();
+        assert_eq!(document.name, "menfie ^A1^C3^b2");
+
+        document.retain(|key, _| key.is_uppercase()).await.unwrap();
+        assert_eq!(document.name, "menfie ^A1^C3");
+    }
+
+    #[tokio::test]
+    async fn clear() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        document.clear().await;
+        assert_eq!(document.name, "menfie");
+
+        let mut document = Document::new("John Smith ^AFOO^bBAR".to_owned(), '^');
+        document.clear().await;
+        assert_eq!(document.name, "John Smith");
+        assert!(document.is_empty());
+    }
+
+    #[tokio::test]
+    async fn prefix_in_value() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "foo^bar").await.unwrap();
+        assert_eq!(document.name, "menfie ^Afoo^^bar");
+        assert_eq!(document.keys().collect::<Vec<_>>(), vec!['A']);
+        assert_eq!(document.fetch('A').await.as_deref(), Some("foo^bar"));
+
+        document.insert('b', "^").await.unwrap();
+        document.insert('c', "^^").await.unwrap();
+        assert_eq!(document.name, "menfie ^Afoo^^bar^b^^^c^^^^");
+        assert_eq!(document.keys().collect::<Vec<_>>(), vec!['A', 'b', 'c']);
+        assert_eq!(document.fetch('b').await.as_deref(), Some("^"));
+        assert_eq!(document.fetch('c').await.as_deref(), Some("^^"));
+        assert_eq!(document.base_name(), "menfie");
+
+        document.insert_multi('r', vec!["1", "^2"]).await.unwrap();
+        assert_eq!(document.fetch_multi('r').await, vec!["1", "^2"]);
+        assert_eq!(
+            Document::try_from_nickname(document.name.clone(), '^'),
+            Ok(document)
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_missing() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(
+            document.delete::<String>('A', None).await,
+            Err(DocumentError::KeyNotFound('A'))
+        );
+    }
+
+    #[tokio::test]
+    async fn entries() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert!(document.entries().is_empty());
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+
+        let entries = document.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.get(&'A').map(String::as_str), Some("FOO"));
+        assert_eq!(entries.get(&'b').map(String::as_str), Some("BAR"));
+    }
+
+    #[tokio::test]
+    async fn fetch_all() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert!(document.fetch_all().await.is_empty());
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(
+            document.fetch_all().await,
+            vec![('A', "FOO".to_owned())]
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn iter() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('b', "BAR").await.unwrap();
+        document.insert('A', "FOO").await.unwrap();
+
+        let mut entries = Vec::new();
+        for (key, value) in &document {
+            entries.push((key, value.into_owned()));
+        }
+        assert_eq!(
+            entries,
+            vec![('A', "FOO".to_owned()), ('b', "BAR".to_owned())]
+        );
+    }
+
+    #[tokio::test]
+    async fn keys() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.keys().next(), None);
+        document.insert('c', "BAZ").await.unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        assert_eq!(document.keys().collect::<Vec<_>>(), vec!['A', 'b', 'c']);
+    }
+
+    #[tokio::test]
+    async fn values() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert!(document.values().is_empty());
+        document.insert('b', "BAR").await.unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.values(), vec!["FOO", "BAR"]);
+    }
+
+    #[tokio::test]
+    async fn len() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.len(), 0);
+        assert!(document.is_empty());
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        assert_eq!(document.len(), 2);
+        assert!(!document.is_empty());
+
+        let document = Document::new("men^ fie^".to_owned(), '^');
+        assert_eq!(document.len(), 0);
+        assert!(document.is_empty());
+    }
+
+    #[tokio::test]
+    async fn len_does_not_touch_last_access() {
+        let document = Document::new("menfie ^AFOO".to_owned(), '^');
+        let last_access = document.last_accessed().await;
+        assert_eq!(document.len(), 1);
+        assert!(!document.is_empty());
+        assert_eq!(document.last_accessed().await, last_access);
+    }
+
+    #[tokio::test]
+    async fn remaining_capacity() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.remaining_capacity(), 25);
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.remaining_capacity(), 20);
+        document.insert('b', "x".repeat(18)).await.unwrap();
+        assert_eq!(document.remaining_capacity(), 0);
+        assert_eq!(
+            document.insert('c', "x").await,
+            Err(DocumentError::NicknameTooLong {
+                length: 35,
+                excess: 3
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn clone() {
+        let document = Document::new("menfie".to_owned(), '^');
+        let clone = document.clone();
+        assert!(!Arc::ptr_eq(&document.last_read, &clone.last_read));
+        assert!(!Arc::ptr_eq(&document.last_written, &clone.last_written));
+        assert_eq!(document.last_accessed().await, clone.last_accessed().await);
+    }
+
+    #[tokio::test]
+    async fn key_of() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_many(vec![('c', "FOO"), ('b', "FOO"), ('A', "BAR")])
+            .await
+            .unwrap();
+        assert!(document.contains_value("FOO"));
+        assert!(!document.contains_value("BAZ"));
+        assert_eq!(document.key_of("FOO"), Some('b'));
+        assert_eq!(document.key_of("BAR"), Some('A'));
+        assert_eq!(document.key_of("BAZ"), None);
+
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('l', "New York").await.unwrap();
+        assert!(document.contains_value("FOO"));
+        assert!(document.contains_value("New York"));
+        assert!(!document.contains_value("BAR"));
+        assert!(!document.contains_value("New"));
+    }
+
+    #[test]
+    fn diff_ignores_encoding_order() {
+        let sorted = Document::new("menfie ^AFOO^bBAR".to_owned(), '^');
+        let unsorted = Document::new("menfie ^bBAR^AFOO".to_owned(), '^');
+        assert_ne!(sorted, unsorted);
+        assert!(sorted.diff(&unsorted).is_empty());
+    }
+
+    #[tokio::test]
+    async fn diff() {
+        let mut old = Document::new("menfie".to_owned(), '^');
+        old.insert_many(vec![('A', "FOO"), ('b', "BAR"), ('c', "BAZ")])
+            .await
+            .unwrap();
+        assert!(old.diff(&old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.delete::<String>('A', None).await.unwrap();
+        new.insert('b', "QUX").await.unwrap();
+        new.insert('d', "NEW").await.unwrap();
+
+        let diff = old.diff(&new);
+        assert!(!diff.is_empty());
+        assert_eq!(
+            diff.added,
+            vec![('d', "NEW".to_owned())]
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        );
+        assert_eq!(
+            diff.removed,
+            vec![('A', "FOO".to_owned())]
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        );
+        assert_eq!(
+            diff.changed,
+            vec![('b', ("BAR".to_owned(), "QUX".to_owned()))]
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn base_name() {
+        let mut document = Document::new("John Smith".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.base_name(), "John Smith");
+        assert_eq!(Document::new("^AFOO^bBAR".to_owned(), '^').base_name(), "");
+    }
+
+    #[tokio::test]
+    async fn set_base_name() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_many(vec![('A', "FOO"), ('b', "BAR")])
+            .await
+            .unwrap();
+        document.set_base_name("bob").unwrap();
+        assert_eq!(document.name, "bob ^AFOO^bBAR");
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+        assert_eq!(document.fetch('b').await.as_deref(), Some("BAR"));
+
+        assert!(document.set_base_name("bob ^cBAZ").is_err());
+        assert!(document
+            .set_base_name(&"x".repeat(MAX_NICKNAME_LEN))
+            .is_err());
+        assert_eq!(document.name, "bob ^AFOO^bBAR");
+    }
+
+    #[tokio::test]
+    async fn display() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        assert_eq!(document.to_string(), "menfie");
+        document.insert('b', "BAR").await.unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.to_string(), "menfie [A=FOO, b=BAR]");
+        assert_eq!(document.encoded_name(), "menfie ^AFOO^bBAR");
+    }
+
+    #[tokio::test]
+    async fn eq() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+        let mut other = Document::new("menfie".to_owned(), '^');
+        assert_ne!(document, other);
+
+        other.insert('A', "FOO").await.unwrap();
+        *other.last_written.write().await = Utc::now() - Duration::hours(1);
+        assert_eq!(document, other);
+        assert_ne!(document, Document::new("menfie ~AFOO".to_owned(), '~'));
+    }
+
+    #[tokio::test]
+    async fn clone_is_independent() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document.insert('A', "FOO").await.unwrap();
+
+        let mut clone = document.clone();
+        assert_eq!(clone.name, document.name);
+        clone.insert('b', "BAR").await.unwrap();
+        assert_eq!(document.name, "menfie ^AFOO");
+        assert_eq!(clone.name, "menfie ^AFOO^bBAR");
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn serde_round_trip() {
+        let mut document = Document::new("menfie".to_owned(), '~');
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+
+        let json = serde_json::to_string(&document).unwrap();
+        assert_eq!(json, r#"{"name":"menfie ~AFOO~bBAR","prefix":"~"}"#);
+
+        let document: Document = serde_json::from_str(&json).unwrap();
+        assert_eq!(document.name, "menfie ~AFOO~bBAR");
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+        assert_eq!(document.fetch('b').await.as_deref(), Some("BAR"));
+    }
+
+    #[test]
+    fn regex_cache() {
+        let document = Document::new("menfie".to_owned(), '%');
+        assert!(super::regex_cache().lock().unwrap().contains_key(&'%'));
+        assert_eq!(
+            Document::new("bob".to_owned(), '%').regex.as_str(),
+            document.regex.as_str()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn deserialize_fresh_last_access() {
+        let document: Document =
+            serde_json::from_str(r#"{"name":"menfie ^AFOO","prefix":"^"}"#).unwrap();
+        assert!(document.since_last_access().await < Duration::seconds(1));
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+    }
+
+    #[tokio::test]
+    async fn migrate_prefix() {
+        let mut document = Document::new("menfie".to_owned(), '^');
+        document
+            .insert_many(vec![('x', "42"), ('p', "5$")])
+            .await
+            .unwrap();
+        document.migrate_prefix('$').unwrap();
+        assert_eq!(document.name, "menfie $p5$$$x42");
+        assert_eq!(document.fetch('x').await.as_deref(), Some("42"));
+        assert_eq!(document.fetch('p').await.as_deref(), Some("5$"));
+        assert!(document.exists('x'));
+        assert_eq!(document, Document::new("menfie $p5$$$x42".to_owned(), '$'));
+
+        assert_eq!(
+            document.migrate_prefix('a'),
+            Err(DocumentError::InvalidPrefix('a'))
+        );
+        let mut document = Document::new("m§nfie".to_owned(), '^');
+        assert_eq!(
+            document.migrate_prefix('§'),
+            Err(DocumentError::InvalidValue("m§nfie".to_owned()))
+        );
+        assert_eq!(document.name, "m§nfie");
+    }
+
+    #[tokio::test]
+    async fn from_kv() {
+        let kv = vec![('b', "BAR".to_owned()), ('A', "F^O".to_owned())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let document = Document::from_kv("menfie".to_owned(), '^', kv.clone()).unwrap();
+        assert_eq!(document.name, "menfie ^AF^^O^bBAR");
+        assert_eq!(document.fetch('A').await.as_deref(), Some("F^O"));
+        assert_eq!(HashMap::from(document), kv);
+
+        let document = Document::from_kv("menfie".to_owned(), '^', HashMap::new()).unwrap();
+        assert_eq!(document.name, "menfie");
+        assert_eq!(HashMap::from(document), HashMap::new());
+
+        let long = vec![('A', "x".repeat(30))].into_iter().collect();
+        assert!(matches!(
+            Document::from_kv("menfie".to_owned(), '^', long),
+            Err(DocumentError::NicknameTooLong { .. })
+        ));
+        let invalid = vec![('^', "FOO".to_owned())].into_iter().collect();
+        assert_eq!(
+            Document::from_kv("menfie".to_owned(), '^', invalid),
+            Err(DocumentError::InvalidKey('^'))
+        );
+        assert!(Document::from_kv("m ^AFOO".to_owned(), '^', HashMap::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn custom_prefix() {
+        let mut document = Document::new("menfie".to_owned(), '~');
+        document.insert('A', "FOO").await.unwrap();
+        assert_eq!(document.name, "menfie ~AFOO");
+        assert_eq!(document.fetch('A').await.as_deref(), Some("FOO"));
+        assert!(document.exists('A'));
+    }
+
+    #[tokio::test]
+    async fn regex_metacharacter_prefix() {
+        let mut document = Document::new("bob".to_owned(), '$');
+        document.insert('x', "42").await.unwrap();
+        assert_eq!(document.name, "bob $x42");
+        assert_eq!(document.fetch('x').await.as_deref(), Some("42"));
+    }
+
+    /// At most three pairs with values of up to three printable characters,
+    /// which always fits in a nickname even if every character is the prefix.
+    fn pairs() -> impl proptest::strategy::Strategy<Value = BTreeMap<char, String>> {
+        use proptest::prelude::*;
+
+        let key = "[a-zA-Z0-9_]".prop_map(|key| key.chars().next().unwrap());
+        let value = "[ -~]{0,3}".prop_filter("trailing whitespace", |value| !value.ends_with(' '));
+        prop::collection::btree_map(key, value, 0..=3)
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn fetch_returns_inserted(pairs in pairs()) {
+            block_on(async {
+                let mut document = Document::try_new("x".to_owned(), '^').unwrap();
+                for (key, value) in &pairs {
+                    document.insert(*key, value.as_str()).await.unwrap();
+                }
+                for (key, value) in &pairs {
+                    proptest::prop_assert_eq!(
+                        document.fetch(*key).await.as_deref(),
+                        Some(value.as_str())
+                    );
+                }
+                proptest::prop_assert_eq!(document.len(), pairs.len());
+
+                Ok(())
+            })?;
+        }
+
+        #[test]
+        fn nickname_round_trip(pairs in pairs()) {
+            block_on(async {
+                let mut document = Document::try_new("x".to_owned(), '^').unwrap();
+                document.insert_many(pairs.clone()).await.unwrap();
+
+                let parsed = Document::try_from_nickname(document.name.clone(), '^').unwrap();
+                proptest::prop_assert_eq!(parsed.kv_chain(), pairs);
+                proptest::prop_assert_eq!(parsed.base_name(), "x");
+
+                Ok(())
+            })?;
+        }
     }
 }
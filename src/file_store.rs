@@ -0,0 +1,128 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Document, StoreError};
+
+/// Keeps each document as a JSON file named after its id in one directory.
+/// Unlike a [`Store`](crate::Store) it keeps the prefix next to the name.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns `None` if nothing was saved under `id`, and an error if the
+    /// file cannot be parsed.
+    pub async fn load(&self, id: &str) -> Result<Option<Document>, StoreError> {
+        let json = match tokio::fs::read(self.path(id)?).await {
+            Ok(json) => json,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(StoreError::Backend(Box::new(err))),
+        };
+
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|err| StoreError::Backend(Box::new(err)))
+    }
+
+    /// Writes to a temporary file first so that a crash never leaves a
+    /// partially written document behind.
+    pub async fn save(&self, id: &str, document: &Document) -> Result<(), StoreError> {
+        let json =
+            serde_json::to_vec(document).map_err(|err| StoreError::Backend(Box::new(err)))?;
+
+        let path = self.path(id)?;
+        let temporary = path.with_extension("json.tmp");
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|err| StoreError::Backend(Box::new(err)))?;
+        tokio::fs::write(&temporary, json)
+            .await
+            .map_err(|err| StoreError::Backend(Box::new(err)))?;
+        tokio::fs::rename(&temporary, &path)
+            .await
+            .map_err(|err| StoreError::Backend(Box::new(err)))
+    }
+
+    /// Rejects ids that would name a file outside of the directory, like
+    /// `../x` or `a/b`.
+    fn path(&self, id: &str) -> Result<PathBuf, StoreError> {
+        if id.is_empty() || id == "." || id == ".." || id.contains(&['/', '\\', '\0'][..]) {
+            return Err(StoreError::Backend(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid id {:?}", id),
+            ))));
+        }
+
+        Ok(self.dir.join(format!("{}.json", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nicknamedb-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let store = FileStore::new(temp_dir("round-trip"));
+        assert!(store.load("1").await.unwrap().is_none());
+
+        let mut document = Document::try_new("menfie".to_owned(), '~').unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        store.save("1", &document).await.unwrap();
+
+        let loaded = store.load("1").await.unwrap().unwrap();
+        assert_eq!(loaded, document);
+        assert_eq!(loaded.fetch('A').await.as_deref(), Some("FOO"));
+
+        std::fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_file() {
+        let store = FileStore::new(temp_dir("corrupt"));
+        std::fs::create_dir_all(store.dir()).unwrap();
+        std::fs::write(store.path("1").unwrap(), r#"{"name":"menfie ^AF"#).unwrap();
+        assert!(matches!(store.load("1").await, Err(StoreError::Backend(_))));
+
+        std::fs::write(
+            store.path("1").unwrap(),
+            r#"{"name":"menfie","prefix":"a"}"#,
+        )
+        .unwrap();
+        assert!(store.load("1").await.is_err());
+
+        std::fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn path_traversal() {
+        let store = FileStore::new(temp_dir("path-traversal").join("store"));
+        let document = Document::try_new("menfie".to_owned(), '~').unwrap();
+        for id in &["../../x", "..", ".", "", "a/b", "a\\b", "a\0b"] {
+            assert!(matches!(
+                store.save(id, &document).await,
+                Err(StoreError::Backend(_))
+            ));
+            assert!(matches!(store.load(id).await, Err(StoreError::Backend(_))));
+        }
+        assert!(!store.dir().parent().unwrap().exists());
+
+        store.save("..1", &document).await.unwrap();
+        assert_eq!(store.load("..1").await.unwrap(), Some(document));
+
+        std::fs::remove_dir_all(store.dir().parent().unwrap()).unwrap();
+    }
+}
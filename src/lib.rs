@@ -1,4 +1,17 @@
 mod document;
-pub use document::Document;
+#[cfg(feature = "history")]
+pub use document::HistoryEntry;
+#[cfg(feature = "metrics")]
+pub use document::OperationCounts;
+pub use document::{
+    ChangeKind, ConflictStrategy, Document, DocumentDiff, DocumentError, DocumentSnapshot, Entry,
+    Iter, NamespaceView, TimeoutDocument, TransactionGuard, DEFAULT_PREFIX, MAX_NICKNAME_LEN,
+};
 mod serenity;
-pub use crate::serenity::{get, NicknameDb, SerenityInit};
+pub use crate::serenity::{get, NicknameDb, NicknameDbError, SerenityInit, SerenityStore};
+mod store;
+pub use store::{MemoryStore, Store, StoreError};
+#[cfg(feature = "file")]
+mod file_store;
+#[cfg(feature = "file")]
+pub use file_store::FileStore;
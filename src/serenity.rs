@@ -1,16 +1,170 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "file")]
+use std::io;
+#[cfg(feature = "file")]
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
-use futures::lock::Mutex;
+use async_trait::async_trait;
+#[cfg(feature = "file")]
+use chrono::TimeZone;
+use chrono::{DateTime, Duration, Utc};
+use futures::{lock::Mutex, Stream, StreamExt};
+use lru::LruCache;
+#[cfg(feature = "file")]
+use serde::{Deserialize, Serialize};
 use serenity::{
     client::{ClientBuilder, Context},
-    model::guild::Member,
+    http::{Http, HttpError},
+    model::{
+        guild::Member,
+        id::{GuildId, UserId},
+        ModelError,
+    },
     prelude::TypeMapKey,
 };
+#[cfg(feature = "file")]
+use tokio::io::AsyncWriteExt;
 
-use crate::Document;
+use crate::document::validate_prefix;
+use crate::{
+    ChangeKind, Document, DocumentError, Store, StoreError, DEFAULT_PREFIX, MAX_NICKNAME_LEN,
+};
+
+#[derive(Debug)]
+pub enum NicknameDbError {
+    Document(DocumentError),
+    Serenity(serenity::Error),
+    /// Discord refused the edit because the bot lacks the permission, e.g.
+    /// Manage Nicknames or a role high enough above the member.
+    PermissionDenied(serenity::Error),
+}
+
+impl fmt::Display for NicknameDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NicknameDbError::Document(err) => fmt::Display::fmt(err, f),
+            NicknameDbError::Serenity(err) => fmt::Display::fmt(err, f),
+            NicknameDbError::PermissionDenied(err) => {
+                write!(f, "missing permission to edit the nickname: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for NicknameDbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NicknameDbError::Document(err) => Some(err),
+            NicknameDbError::Serenity(err) => Some(err),
+            NicknameDbError::PermissionDenied(err) => Some(err),
+        }
+    }
+}
+
+impl From<DocumentError> for NicknameDbError {
+    fn from(err: DocumentError) -> Self {
+        NicknameDbError::Document(err)
+    }
+}
+
+impl From<serenity::Error> for NicknameDbError {
+    fn from(err: serenity::Error) -> Self {
+        if is_permission_error(&err) {
+            NicknameDbError::PermissionDenied(err)
+        } else {
+            NicknameDbError::Serenity(err)
+        }
+    }
+}
+
+/// Both the cache-based permission check and a 403 from Discord itself.
+fn is_permission_error(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Model(ModelError::InvalidPermissions(_)) => true,
+        serenity::Error::Http(err) => matches!(
+            &**err,
+            HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 403
+        ),
+        _ => false,
+    }
+}
+
+/// A cached document's guild and member.
+type CacheKey = (GuildId, UserId);
 
 pub struct NicknameDb {
     prefix: char,
+    guild_prefixes: RwLock<HashMap<GuildId, char>>,
+    documents: Mutex<HashMap<CacheKey, Arc<Mutex<Document>>>>,
+    capacity: Option<usize>,
+    /// Cached documents by when they were last requested, only kept when
+    /// there is a capacity.
+    recency: std::sync::Mutex<LruCache<CacheKey, ()>>,
+    saves: std::sync::Mutex<SaveLimiter>,
+    hooks: RwLock<Vec<ChangeHook>>,
+    #[cfg(feature = "file")]
+    persistence: Option<Persistence>,
+}
+
+type ChangeHook = Arc<dyn Fn(UserId, char, ChangeKind) + Send + Sync>;
+
+/// Tracks when each member was last saved, so that saves within the save
+/// interval are held back and coalesced.
+#[derive(Debug)]
+struct SaveLimiter {
+    interval: Duration,
+    last_saved: HashMap<CacheKey, DateTime<Utc>>,
+    pending: HashSet<CacheKey>,
+}
+
+impl SaveLimiter {
+    fn new() -> Self {
+        Self {
+            interval: Duration::zero(),
+            last_saved: HashMap::new(),
+            pending: HashSet::new(),
+        }
+    }
+
+    fn is_due(&self, key: &CacheKey, now: DateTime<Utc>) -> bool {
+        match self.last_saved.get(key) {
+            Some(last_saved) => now - *last_saved >= self.interval,
+            None => true,
+        }
+    }
+
+    /// Whether a save requested at `now` can go out right away. If not, it is
+    /// left pending.
+    fn try_save(&mut self, key: CacheKey, now: DateTime<Utc>) -> bool {
+        if !self.is_due(&key, now) {
+            self.pending.insert(key);
+            return false;
+        }
+
+        self.pending.remove(&key);
+        self.last_saved.insert(key, now);
+        true
+    }
+
+    /// Takes the pending saves that are due at `now`, or all of them if
+    /// `force` is set.
+    fn take_pending(&mut self, now: DateTime<Utc>, force: bool) -> Vec<CacheKey> {
+        let due = self
+            .pending
+            .iter()
+            .filter(|key| force || self.is_due(key, now))
+            .copied()
+            .collect::<Vec<_>>();
+        for key in &due {
+            self.pending.remove(key);
+            self.last_saved.insert(*key, now);
+        }
+
+        due
+    }
 }
 
 impl TypeMapKey for NicknameDb {
@@ -18,21 +172,779 @@ impl TypeMapKey for NicknameDb {
 }
 
 impl NicknameDb {
-    pub async fn get_document(&self, member: Member) -> Arc<Mutex<Document>> {
-        Arc::new(Mutex::new(Document::new(
-            member.display_name().to_string(),
-            self.prefix,
-        )))
+    /// Fails with [`DocumentError::InvalidPrefix`], wrapped in
+    /// [`NicknameDbError::Document`], for the prefixes [`Document::try_new`]
+    /// rejects.
+    pub fn new(prefix: char) -> Result<Self, NicknameDbError> {
+        Self::with_guild_prefixes(prefix, HashMap::new())
+    }
+
+    /// Shorthand for [`NicknameDb::new`] followed by [`NicknameDb::capacity`].
+    pub fn with_capacity(prefix: char, capacity: usize) -> Result<Self, NicknameDbError> {
+        Ok(Self::new(prefix)?.capacity(capacity))
+    }
+
+    /// Shorthand for [`NicknameDb::new`] followed by
+    /// [`NicknameDb::persistence`]. An invalid prefix fails with
+    /// [`io::ErrorKind::InvalidInput`].
+    #[cfg(feature = "file")]
+    pub fn with_persistence(prefix: char, path: PathBuf) -> io::Result<Self> {
+        Self::new(prefix)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?
+            .persistence(path)
+    }
+
+    /// `prefix` is used for every guild that has no entry in `guild_prefixes`.
+    pub fn with_guild_prefixes(
+        prefix: char,
+        guild_prefixes: HashMap<GuildId, char>,
+    ) -> Result<Self, NicknameDbError> {
+        validate_prefix(prefix)?;
+        for prefix in guild_prefixes.values() {
+            validate_prefix(*prefix)?;
+        }
+
+        Ok(Self {
+            prefix,
+            guild_prefixes: RwLock::new(guild_prefixes),
+            documents: Mutex::new(HashMap::new()),
+            capacity: None,
+            recency: std::sync::Mutex::new(LruCache::unbounded()),
+            saves: std::sync::Mutex::new(SaveLimiter::new()),
+            hooks: RwLock::new(Vec::new()),
+            #[cfg(feature = "file")]
+            persistence: None,
+        })
+    }
+
+    /// Keeps at most `capacity` documents cached, dropping the least recently
+    /// requested one when another is added. Documents that are still held
+    /// outside the cache, locked or have a pending save are never dropped, so
+    /// the cache can briefly hold more.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self.trim_loaded();
+        self
+    }
+
+    /// Loads the documents saved in `path`, a file of one JSON record per
+    /// line, and appends every document evicted from the cache to it from
+    /// then on. A missing file starts an empty cache. Call
+    /// [`NicknameDb::persist`] on shutdown to save the rest.
+    #[cfg(feature = "file")]
+    pub fn persistence(mut self, path: PathBuf) -> io::Result<Self> {
+        let mut loaded = match std::fs::read_to_string(&path) {
+            Ok(contents) => parse_persisted(&contents)?.into_iter().collect::<Vec<_>>(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        loaded.sort_by_key(|(_, document)| document.last_accessed_now());
+
+        let documents = self.documents.get_mut();
+        let recency = self.recency.get_mut().expect("recency");
+        for (key, document) in loaded {
+            documents
+                .entry(key)
+                .or_insert_with(|| Arc::new(Mutex::new(document)));
+            recency.put(key, ());
+        }
+        self.persistence = Some(Persistence {
+            path,
+            lock: Mutex::new(()),
+        });
+        self.trim_loaded();
+
+        Ok(self)
+    }
+
+    /// Drops the least recently accessed of the documents loaded by
+    /// [`NicknameDb::persistence`] that don't fit in the capacity. Their
+    /// records stay in the file, like those of evicted documents.
+    fn trim_loaded(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+
+        let documents = self.documents.get_mut();
+        let recency = self.recency.get_mut().expect("recency");
+        while documents.len() > capacity {
+            match recency.pop_lru() {
+                Some((key, ())) => {
+                    documents.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Rewrites the persistence file with every cached document, keeping the
+    /// records of the documents that were evicted. Does nothing without
+    /// [`NicknameDb::persistence`].
+    #[cfg(feature = "file")]
+    pub async fn persist(&self) -> io::Result<()> {
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence,
+            None => return Ok(()),
+        };
+
+        // Holding the file keeps evictions and invalidations out until the
+        // snapshot is written. Evictions only try to take it, as their caller
+        // may hold one of the documents locked below. Locking a document while
+        // holding the cache could deadlock with a caller that holds the
+        // document and asks for another one.
+        let _written = persistence.lock.lock().await;
+        let cached = self
+            .documents
+            .lock()
+            .await
+            .iter()
+            .map(|(key, document)| (*key, document.clone()))
+            .collect::<Vec<_>>();
+        let mut snapshot = Vec::with_capacity(cached.len());
+        for (key, document) in cached {
+            snapshot.push((key, document.lock().await.clone()));
+        }
+
+        let mut persisted = persistence.read().await?;
+        persisted.extend(snapshot);
+        persistence.replace(&persisted).await
+    }
+
+    /// Takes the persistence file for an eviction, or returns `None` if it is
+    /// busy and nothing should be evicted this time. Hold the result until
+    /// the evicted documents have gone through [`NicknameDb::persist_evicted`].
+    #[cfg(feature = "file")]
+    fn try_lock_file(&self) -> Option<Option<futures::lock::MutexGuard<'_, ()>>> {
+        match &self.persistence {
+            Some(persistence) => persistence.lock.try_lock().map(Some),
+            None => Some(None),
+        }
+    }
+
+    #[cfg(not(feature = "file"))]
+    fn try_lock_file(&self) -> Option<()> {
+        Some(())
+    }
+
+    /// Appends documents already dropped from the cache to the persistence
+    /// file, if there is one. The cache must not be locked by the caller, and
+    /// the file must be held through [`NicknameDb::try_lock_file`]. If
+    /// that fails, the documents are cached again, unless the member was
+    /// cached anew in the meantime, and `false` is returned.
+    #[cfg_attr(not(feature = "file"), allow(unused_variables))]
+    async fn persist_evicted(&self, evicted: Vec<(CacheKey, Arc<Mutex<Document>>)>) -> bool {
+        #[cfg(feature = "file")]
+        {
+            if let Some(persistence) = &self.persistence {
+                if !evicted.is_empty() && persistence.append(&evicted).await.is_err() {
+                    let mut documents = self.documents.lock().await;
+                    let mut recency = self.recency.lock().expect("recency");
+                    for (key, document) in evicted {
+                        documents.entry(key).or_insert(document);
+                        if self.capacity.is_some() {
+                            recency.put(key, ());
+                        }
+                    }
+
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Registers a hook that is called after every successful
+    /// [`NicknameDb::insert`] and [`NicknameDb::delete`], once the document
+    /// lock has been released. Changes made directly on a document returned by
+    /// [`NicknameDb::get_document`] are not reported.
+    pub fn on_change<F>(&self, hook: F)
+    where
+        F: Fn(UserId, char, ChangeKind) + Send + Sync + 'static,
+    {
+        self.hooks.write().expect("hooks").push(Arc::new(hook));
+    }
+
+    /// Inserts into the member's cached document.
+    pub async fn insert<T: Into<String>>(
+        &self,
+        member: &Member,
+        key: char,
+        value: T,
+    ) -> Result<(), NicknameDbError> {
+        let document = self.get_document(member).await?;
+        Ok(self
+            .insert_cached(&document, member.user.id, key, value.into())
+            .await?)
+    }
+
+    /// Deletes from the member's cached document.
+    pub async fn delete(&self, member: &Member, key: char) -> Result<(), NicknameDbError> {
+        let document = self.get_document(member).await?;
+        Ok(self.delete_cached(&document, member.user.id, key).await?)
+    }
+
+    async fn insert_cached(
+        &self,
+        document: &Mutex<Document>,
+        user_id: UserId,
+        key: char,
+        value: String,
+    ) -> Result<(), DocumentError> {
+        document.lock().await.insert(key, value).await?;
+        self.notify(user_id, key, ChangeKind::Insert);
+
+        Ok(())
+    }
+
+    async fn delete_cached(
+        &self,
+        document: &Mutex<Document>,
+        user_id: UserId,
+        key: char,
+    ) -> Result<(), DocumentError> {
+        document.lock().await.delete::<String>(key, None).await?;
+        self.notify(user_id, key, ChangeKind::Delete);
+
+        Ok(())
+    }
+
+    /// The hooks are cloned out first so that a hook may register another.
+    fn notify(&self, user_id: UserId, key: char, kind: ChangeKind) {
+        let hooks = self.hooks.read().expect("hooks").clone();
+        for hook in hooks {
+            hook(user_id, key, kind);
+        }
+    }
+
+    /// Limits [`NicknameDb::save`] to one edit per member every `interval`.
+    /// Saves in between are held back until [`NicknameDb::flush_due`] or
+    /// [`NicknameDb::flush`], and then write the latest state.
+    pub fn set_save_interval(&self, interval: Duration) {
+        self.saves.lock().expect("saves").interval = interval;
+    }
+
+    /// Overrides the prefix for documents created in `guild_id` from now on.
+    /// Documents that are already cached keep the prefix they were parsed with.
+    pub fn set_prefix(&self, guild_id: GuildId, prefix: char) -> Result<(), NicknameDbError> {
+        validate_prefix(prefix)?;
+        self.guild_prefixes
+            .write()
+            .expect("guild prefixes")
+            .insert(guild_id, prefix);
+
+        Ok(())
+    }
+
+    pub fn prefix_for_guild(&self, guild_id: GuildId) -> char {
+        self.guild_prefixes
+            .read()
+            .expect("guild prefixes")
+            .get(&guild_id)
+            .copied()
+            .unwrap_or(self.prefix)
+    }
+
+    /// Returns the cached document for the member, parsing it from their
+    /// display name the first time it is requested.
+    pub async fn get_document(
+        &self,
+        member: &Member,
+    ) -> Result<Arc<Mutex<Document>>, NicknameDbError> {
+        Ok(self
+            .cached_document(member.guild_id, member.user.id, || {
+                member.display_name().to_string()
+            })
+            .await?)
+    }
+
+    /// Like [`NicknameDb::get_document`] for when only the ids and the current
+    /// display name are at hand. `current_name` is only parsed on a cache miss.
+    pub async fn get_document_by_id(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        current_name: &str,
+    ) -> Result<Arc<Mutex<Document>>, NicknameDbError> {
+        Ok(self
+            .cached_document(guild_id, user_id, || current_name.to_owned())
+            .await?)
+    }
+
+    /// Caches a document for every member of the guild, paging through the
+    /// member list, and returns how many members were seen. Members that
+    /// already have a cached document keep it.
+    pub async fn load_guild(
+        &self,
+        ctx: &Context,
+        guild_id: GuildId,
+    ) -> Result<usize, NicknameDbError> {
+        let members = guild_id
+            .members_iter(&ctx.http)
+            .map(|member| member.map(|member| (member.user.id, member.display_name().to_string())));
+
+        self.load_members(guild_id, members).await
+    }
+
+    async fn load_members<S>(&self, guild_id: GuildId, members: S) -> Result<usize, NicknameDbError>
+    where
+        S: Stream<Item = Result<(UserId, String), serenity::Error>>,
+    {
+        futures::pin_mut!(members);
+
+        let mut count = 0;
+        while let Some(member) = members.next().await {
+            let (user_id, name) = member?;
+            self.cached_document(guild_id, user_id, || name).await?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Writes `doc` back as the member's nickname. Names over
+    /// [`MAX_NICKNAME_LEN`] are rejected before Discord is contacted.
+    pub async fn update_member_nickname(
+        &self,
+        ctx: &Context,
+        member: &mut Member,
+        doc: &Document,
+    ) -> Result<(), NicknameDbError> {
+        *member = edit_nickname(ctx, member, &doc.name).await?;
+
+        Ok(())
+    }
+
+    /// Writes the member's cached document back to Discord, or leaves it
+    /// pending if the member was saved less than the save interval ago.
+    pub async fn save(&self, ctx: &Context, member: &Member) -> Result<(), NicknameDbError> {
+        let due = self
+            .saves
+            .lock()
+            .expect("saves")
+            .try_save((member.guild_id, member.user.id), Utc::now());
+        if !due {
+            return Ok(());
+        }
+
+        let document = self.get_document(member).await?;
+        let name = document.lock().await.name.clone();
+        edit_nickname(ctx, member, &name).await?;
+
+        Ok(())
+    }
+
+    /// Writes the pending saves whose save interval has passed. Meant to be
+    /// called periodically, like [`NicknameDb::evict_stale`].
+    pub async fn flush_due(&self, ctx: &Context) -> Result<(), NicknameDbError> {
+        self.write_pending(ctx, false).await
+    }
+
+    /// Writes every pending save right away, e.g. on shutdown. Documents that
+    /// were evicted from the cache in the meantime are skipped.
+    pub async fn flush(&self, ctx: &Context) -> Result<(), NicknameDbError> {
+        self.write_pending(ctx, true).await
+    }
+
+    /// Keeps going after a failed edit and returns the first error.
+    async fn write_pending(&self, ctx: &Context, force: bool) -> Result<(), NicknameDbError> {
+        let mut result = Ok(());
+        for ((guild_id, user_id), name) in self.pending_names(force).await {
+            if let Err(err) = edit_nickname_by_id(ctx, guild_id, user_id, &name).await {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Takes the pending saves along with the names to write, skipping the
+    /// documents that are no longer cached.
+    async fn pending_names(&self, force: bool) -> Vec<(CacheKey, String)> {
+        let pending = self
+            .saves
+            .lock()
+            .expect("saves")
+            .take_pending(Utc::now(), force);
+
+        let mut names = Vec::new();
+        for key in pending {
+            let document = match self.documents.lock().await.get(&key) {
+                Some(document) => document.clone(),
+                None => continue,
+            };
+            names.push((key, document.lock().await.name.clone()));
+        }
+
+        names
+    }
+
+    /// Only fails if a prefix got past the checks in [`NicknameDb::new`] and
+    /// [`NicknameDb::set_prefix`].
+    async fn cached_document(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        name: impl FnOnce() -> String,
+    ) -> Result<Arc<Mutex<Document>>, DocumentError> {
+        let prefix = self.prefix_for_guild(guild_id);
+        let key = (guild_id, user_id);
+
+        let file = self.capacity.and_then(|_| self.try_lock_file());
+        let mut documents = self.documents.lock().await;
+        let document = match documents.get(&key) {
+            Some(document) => document.clone(),
+            None => {
+                let document = Arc::new(Mutex::new(Document::try_new(name(), prefix)?));
+                documents.insert(key, document.clone());
+                document
+            }
+        };
+
+        let evicted = match self.capacity {
+            Some(capacity) => {
+                let mut recency = self.recency.lock().expect("recency");
+                recency.put(key, ());
+                match file {
+                    Some(_) => self.evict_least_recent(&mut documents, &mut recency, capacity),
+                    None => Vec::new(),
+                }
+            }
+            None => Vec::new(),
+        };
+        drop(documents);
+        self.persist_evicted(evicted).await;
+
+        Ok(document)
+    }
+
+    /// Removes and returns the least recently requested documents over
+    /// `capacity`, for [`NicknameDb::persist_evicted`]. Keys in `recency`
+    /// whose document is gone were invalidated or evicted as stale, and are
+    /// dropped on the way.
+    fn evict_least_recent(
+        &self,
+        documents: &mut HashMap<CacheKey, Arc<Mutex<Document>>>,
+        recency: &mut LruCache<CacheKey, ()>,
+        capacity: usize,
+    ) -> Vec<(CacheKey, Arc<Mutex<Document>>)> {
+        let saves = self.saves.lock().expect("saves");
+        let mut excess = documents.len().saturating_sub(capacity);
+        let mut dropped = Vec::new();
+        // The most recently requested document is never a candidate. Documents
+        // that can't be dropped keep their place. One that is still held
+        // elsewhere would be cached again as a second copy on the next request,
+        // and the two would overwrite each other's writes.
+        for (key, _) in recency.iter().rev().take(recency.len().saturating_sub(1)) {
+            if excess == 0 {
+                break;
+            }
+
+            match documents.get(key) {
+                Some(document) => {
+                    if Arc::strong_count(document) == 1
+                        && document.try_lock().is_some()
+                        && !saves.pending.contains(key)
+                    {
+                        dropped.push(*key);
+                        excess -= 1;
+                    }
+                }
+                None => dropped.push(*key),
+            }
+        }
+
+        let mut evicted = Vec::new();
+        for key in dropped {
+            if let Some(document) = documents.remove(&key) {
+                evicted.push((key, document));
+            }
+            recency.pop(&key);
+        }
+
+        evicted
+    }
+
+    /// Drops the user's cached documents in every guild. Records already in
+    /// the persistence file are loaded again on the next start, unless they
+    /// are dropped with [`NicknameDb::invalidate_persisted`].
+    pub async fn invalidate(&self, user_id: UserId) {
+        let mut documents = self.documents.lock().await;
+        let mut recency = self.recency.lock().expect("recency");
+        documents.retain(|key, _| {
+            if key.1 == user_id {
+                recency.pop(key);
+                return false;
+            }
+
+            true
+        });
+    }
+
+    /// Like [`NicknameDb::invalidate`], and also drops the user's records
+    /// from the persistence file. Fails if the file can't be read, parsed or
+    /// rewritten, in which case the cache has already been cleared.
+    #[cfg(feature = "file")]
+    pub async fn invalidate_persisted(&self, user_id: UserId) -> io::Result<()> {
+        self.invalidate(user_id).await;
+
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence,
+            None => return Ok(()),
+        };
+        let _written = persistence.lock.lock().await;
+        let mut persisted = persistence.read().await?;
+        let count = persisted.len();
+        persisted.retain(|key, _| key.1 != user_id);
+        if persisted.len() != count {
+            persistence.replace(&persisted).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops cached documents that have been idle for longer than `max_idle`
+    /// and returns how many were dropped. Documents that are still held
+    /// outside the cache, locked at the time, have a pending save, or can't be
+    /// appended to the persistence file, are left alone. Nothing is dropped
+    /// while [`NicknameDb::persist`] is rewriting the file.
+    pub async fn evict_stale(&self, max_idle: Duration) -> usize {
+        let _file = match self.try_lock_file() {
+            Some(file) => file,
+            None => return 0,
+        };
+        let mut documents = self.documents.lock().await;
+
+        let mut stale = Vec::new();
+        for (key, document) in documents.iter() {
+            if Arc::strong_count(document) > 1 {
+                continue;
+            }
+
+            if let Some(document) = document.try_lock() {
+                if !document.is_stale(max_idle).await {
+                    continue;
+                }
+
+                let pending = self.saves.lock().expect("saves").pending.contains(key);
+                if !pending {
+                    stale.push(*key);
+                }
+            }
+        }
+
+        let mut evicted = Vec::new();
+        {
+            let mut recency = self.recency.lock().expect("recency");
+            for key in stale {
+                if let Some(document) = documents.remove(&key) {
+                    evicted.push((key, document));
+                }
+                recency.pop(&key);
+            }
+        }
+        drop(documents);
+
+        let count = evicted.len();
+        if self.persist_evicted(evicted).await {
+            count
+        } else {
+            0
+        }
+    }
+}
+
+fn validate_nickname(name: &str) -> Result<(), DocumentError> {
+    let length = name.chars().count();
+    if length > MAX_NICKNAME_LEN {
+        return Err(DocumentError::NicknameTooLong {
+            length,
+            excess: length - MAX_NICKNAME_LEN,
+        });
+    }
+
+    Ok(())
+}
+
+async fn edit_nickname(
+    ctx: &Context,
+    member: &Member,
+    name: &str,
+) -> Result<Member, NicknameDbError> {
+    validate_nickname(name)?;
+
+    Ok(member.edit(&ctx.http, |edit| edit.nickname(name)).await?)
+}
+
+/// The file passed to [`NicknameDb::persistence`]. Its lock is held for every
+/// write, and by evictions from when they pick documents until they are
+/// appended, so that [`NicknameDb::persist`] can't snapshot the cache while a
+/// document is on its way to the file.
+#[cfg(feature = "file")]
+struct Persistence {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+#[cfg(feature = "file")]
+impl Persistence {
+    /// A missing file reads as empty.
+    async fn read(&self) -> io::Result<HashMap<CacheKey, Document>> {
+        match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => parse_persisted(&contents),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn replace(&self, documents: &HashMap<CacheKey, Document>) -> io::Result<()> {
+        let mut contents = String::new();
+        for (key, document) in documents {
+            contents += &persisted_line(*key, document)?;
+        }
+
+        let temporary = self.path.with_extension("tmp");
+        tokio::fs::write(&temporary, contents).await?;
+        tokio::fs::rename(&temporary, &self.path).await
+    }
+
+    async fn append(&self, documents: &[(CacheKey, Arc<Mutex<Document>>)]) -> io::Result<()> {
+        let mut contents = String::new();
+        for (key, document) in documents {
+            contents += &persisted_line(*key, &*document.lock().await)?;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(contents.as_bytes()).await?;
+        // A tokio file finishes writing in the background unless flushed.
+        file.flush().await
+    }
+}
+
+/// A line of the file passed to [`NicknameDb::persistence`].
+#[cfg(feature = "file")]
+#[derive(Serialize, Deserialize)]
+struct PersistedDocument {
+    guild_id: u64,
+    user_id: u64,
+    #[serde(flatten)]
+    document: Document,
+    /// Epoch seconds.
+    last_access: i64,
+}
+
+/// Later records for the same member win.
+#[cfg(feature = "file")]
+fn parse_persisted(contents: &str) -> io::Result<HashMap<CacheKey, Document>> {
+    let mut documents = HashMap::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let record: PersistedDocument = serde_json::from_str(line)?;
+        let last_access = Utc
+            .timestamp_opt(record.last_access, 0)
+            .single()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("last access out of range: {}", record.last_access),
+                )
+            })?;
+        let mut document = record.document;
+        document.set_last_accessed(last_access);
+        documents.insert((GuildId(record.guild_id), UserId(record.user_id)), document);
+    }
+
+    Ok(documents)
+}
+
+#[cfg(feature = "file")]
+fn persisted_line(key: CacheKey, document: &Document) -> io::Result<String> {
+    let (guild_id, user_id) = key;
+    let record = PersistedDocument {
+        guild_id: guild_id.0,
+        user_id: user_id.0,
+        document: document.clone(),
+        last_access: document.last_accessed_now().timestamp(),
+    };
+
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    Ok(line)
+}
+
+async fn edit_nickname_by_id(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    name: &str,
+) -> Result<(), NicknameDbError> {
+    validate_nickname(name)?;
+
+    guild_id
+        .edit_member(&ctx.http, user_id, |edit| edit.nickname(name))
+        .await?;
+
+    Ok(())
+}
+
+/// A [`Store`] backed by the nicknames of a guild's members. Ids are user ids,
+/// and a member without a nickname loads as their username.
+pub struct SerenityStore {
+    http: Arc<Http>,
+    guild_id: GuildId,
+}
+
+impl SerenityStore {
+    pub fn new(http: Arc<Http>, guild_id: GuildId) -> Self {
+        Self { http, guild_id }
     }
 }
 
-pub trait SerenityInit {
-    fn register_nicknamedb(self, prefix: char) -> Self;
+#[async_trait]
+impl Store for SerenityStore {
+    async fn load(&self, id: &str) -> Option<String> {
+        let user_id = UserId(id.parse().ok()?);
+        let member = self.guild_id.member(&self.http, user_id).await.ok()?;
+
+        Some(member.display_name().to_string())
+    }
+
+    async fn save(&self, id: &str, name: &str) -> Result<(), StoreError> {
+        validate_nickname(name)?;
+        let user_id = UserId(
+            id.parse()
+                .map_err(|err| StoreError::Backend(Box::new(err)))?,
+        );
+
+        self.guild_id
+            .edit_member(&self.http, user_id, |edit| edit.nickname(name))
+            .await
+            .map_err(|err| StoreError::Backend(Box::new(err)))?;
+
+        Ok(())
+    }
+}
+
+pub trait SerenityInit: Sized {
+    /// Fails like [`NicknameDb::new`].
+    fn register_nicknamedb(self, prefix: char) -> Result<Self, NicknameDbError>;
+    fn register_nicknamedb_default(self) -> Self;
 }
 
 impl SerenityInit for ClientBuilder<'_> {
-    fn register_nicknamedb(self, prefix: char) -> Self {
-        self.type_map_insert::<NicknameDb>(Arc::new(NicknameDb { prefix }))
+    fn register_nicknamedb(self, prefix: char) -> Result<Self, NicknameDbError> {
+        Ok(self.type_map_insert::<NicknameDb>(Arc::new(NicknameDb::new(prefix)?)))
+    }
+
+    fn register_nicknamedb_default(self) -> Self {
+        self.register_nicknamedb(DEFAULT_PREFIX)
+            .expect("default prefix")
     }
 }
 
@@ -40,3 +952,554 @@ pub async fn get(ctx: &Context) -> Option<Arc<NicknameDb>> {
     let data = ctx.data.read().await;
     data.get::<NicknameDb>().cloned()
 }
+
+#[cfg(test)]
+mod tests {
+    use serenity::http::error::ErrorResponse;
+    use serenity::model::permissions::Permissions;
+
+    use super::*;
+
+    static_assertions::assert_impl_all!(NicknameDb: Send, Sync);
+
+    #[tokio::test]
+    async fn cached_document() {
+        let db = NicknameDb::new('^').unwrap();
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        first.lock().await.insert('A', "FOO").await.unwrap();
+
+        let second = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.lock().await.name, "menfie ^AFOO");
+
+        let other = db
+            .cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        db.invalidate(UserId(1)).await;
+        let third = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+    }
+
+    #[tokio::test]
+    async fn get_document_by_id() {
+        let db = NicknameDb::new('^').unwrap();
+        let document = db
+            .get_document_by_id(GuildId(1), UserId(1), "menfie ^AFOO")
+            .await
+            .unwrap();
+        document.lock().await.insert('b', "BAR").await.unwrap();
+        assert_eq!(document.lock().await.name, "menfie ^AFOO^bBAR");
+
+        let cached = db
+            .get_document_by_id(GuildId(1), UserId(1), "menfie")
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&document, &cached));
+    }
+
+    #[tokio::test]
+    async fn load_members() {
+        let db = NicknameDb::new('^').unwrap();
+        let cached = db
+            .cached_document(GuildId(1), UserId(1), || "menfie ^AFOO".to_owned())
+            .await
+            .unwrap();
+        let members = vec![
+            Ok((UserId(1), "menfie".to_owned())),
+            Ok((UserId(2), "bob ^bBAR".to_owned())),
+            Ok((UserId(3), "alice".to_owned())),
+        ];
+        assert_eq!(
+            db.load_members(GuildId(1), futures::stream::iter(members))
+                .await
+                .unwrap(),
+            3
+        );
+
+        let documents = db.documents.lock().await;
+        assert_eq!(documents.len(), 3);
+        assert!(Arc::ptr_eq(&documents[&(GuildId(1), UserId(1))], &cached));
+        assert_eq!(
+            documents[&(GuildId(1), UserId(2))].lock().await.name,
+            "bob ^bBAR"
+        );
+    }
+
+    #[tokio::test]
+    async fn on_change() {
+        let db = NicknameDb::new('^').unwrap();
+        let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = changes.clone();
+        db.on_change(move |user_id, key, kind| {
+            recorded.lock().unwrap().push((user_id, key, kind));
+        });
+
+        let document = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        db.insert_cached(&document, UserId(1), 'A', "FOO".to_owned())
+            .await
+            .unwrap();
+        db.insert_cached(&document, UserId(1), '^', "BAR".to_owned())
+            .await
+            .unwrap_err();
+        db.delete_cached(&document, UserId(1), 'A').await.unwrap();
+        db.delete_cached(&document, UserId(1), 'A')
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                (UserId(1), 'A', ChangeKind::Insert),
+                (UserId(1), 'A', ChangeKind::Delete),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn capacity() {
+        let db = NicknameDb::new('^').unwrap().capacity(2);
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(3), || "alice".to_owned())
+            .await
+            .unwrap();
+        {
+            let documents = db.documents.lock().await;
+            assert_eq!(documents.len(), 2);
+            assert!(!documents.contains_key(&(GuildId(1), UserId(2))));
+        }
+
+        let _guard = first.lock().await;
+        db.cached_document(GuildId(1), UserId(4), || "carol".to_owned())
+            .await
+            .unwrap();
+        let documents = db.documents.lock().await;
+        assert_eq!(documents.len(), 2);
+        assert!(documents.contains_key(&(GuildId(1), UserId(1))));
+        assert!(documents.contains_key(&(GuildId(1), UserId(4))));
+    }
+
+    #[tokio::test]
+    async fn capacity_held() {
+        let db = NicknameDb::new('^').unwrap().capacity(1);
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(db.documents.lock().await.len(), 2);
+
+        let again = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+    }
+
+    #[tokio::test]
+    async fn guild_prefix() {
+        let db =
+            NicknameDb::with_guild_prefixes('^', vec![(GuildId(3), '~')].into_iter().collect())
+                .unwrap();
+        db.set_prefix(GuildId(2), '§').unwrap();
+        assert_eq!(db.prefix_for_guild(GuildId(1)), '^');
+        assert_eq!(db.prefix_for_guild(GuildId(2)), '§');
+        assert_eq!(db.prefix_for_guild(GuildId(3)), '~');
+
+        assert!(matches!(
+            db.set_prefix(GuildId(2), 'a'),
+            Err(NicknameDbError::Document(DocumentError::InvalidPrefix('a')))
+        ));
+        assert_eq!(db.prefix_for_guild(GuildId(2)), '§');
+        assert!(matches!(
+            NicknameDb::new('a'),
+            Err(NicknameDbError::Document(DocumentError::InvalidPrefix('a')))
+        ));
+        assert!(matches!(
+            NicknameDb::with_guild_prefixes('^', vec![(GuildId(3), ' ')].into_iter().collect()),
+            Err(NicknameDbError::Document(DocumentError::InvalidPrefix(' ')))
+        ));
+
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        let second = db
+            .cached_document(GuildId(2), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        first.lock().await.insert('A', "FOO").await.unwrap();
+        second.lock().await.insert('A', "FOO").await.unwrap();
+        assert_eq!(first.lock().await.name, "menfie ^AFOO");
+        assert_eq!(second.lock().await.name, "menfie §AFOO");
+    }
+
+    #[tokio::test]
+    async fn evict_stale() {
+        let db = NicknameDb::new('^').unwrap();
+        db.cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        let locked = db
+            .cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        let held = db
+            .cached_document(GuildId(1), UserId(4), || "carol".to_owned())
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        db.cached_document(GuildId(1), UserId(3), || "alice".to_owned())
+            .await
+            .unwrap();
+
+        let _guard = locked.lock().await;
+        assert_eq!(db.evict_stale(Duration::milliseconds(25)).await, 1);
+
+        {
+            let documents = db.documents.lock().await;
+            assert!(!documents.contains_key(&(GuildId(1), UserId(1))));
+            assert!(documents.contains_key(&(GuildId(1), UserId(2))));
+            assert!(documents.contains_key(&(GuildId(1), UserId(3))));
+        }
+        let again = db
+            .cached_document(GuildId(1), UserId(4), || "carol".to_owned())
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&held, &again));
+    }
+
+    #[tokio::test]
+    async fn evict_stale_pending() {
+        let db = NicknameDb::new('^').unwrap();
+        db.set_save_interval(Duration::hours(1));
+        let key = (GuildId(1), UserId(1));
+        let document = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        {
+            let mut saves = db.saves.lock().unwrap();
+            assert!(saves.try_save(key, Utc::now()));
+            assert!(!saves.try_save(key, Utc::now()));
+        }
+        document.lock().await.insert('A', "FOO").await.unwrap();
+        drop(document);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(db.evict_stale(Duration::milliseconds(25)).await, 0);
+        assert_eq!(
+            db.pending_names(true).await,
+            vec![(key, "menfie ^AFOO".to_owned())]
+        );
+        assert_eq!(db.evict_stale(Duration::milliseconds(25)).await, 1);
+    }
+
+    #[test]
+    fn save_limiter() {
+        let mut saves = SaveLimiter::new();
+        saves.interval = Duration::seconds(10);
+        let start = Utc::now();
+        let key = (GuildId(1), UserId(1));
+        let other = (GuildId(1), UserId(2));
+
+        assert!(saves.try_save(key, start));
+        assert!(!saves.try_save(key, start + Duration::seconds(1)));
+        assert!(!saves.try_save(key, start + Duration::seconds(2)));
+        assert!(saves.try_save(other, start + Duration::seconds(2)));
+        assert!(saves
+            .take_pending(start + Duration::seconds(5), false)
+            .is_empty());
+        assert_eq!(
+            saves.take_pending(start + Duration::seconds(10), false),
+            vec![key]
+        );
+        assert!(saves
+            .take_pending(start + Duration::seconds(30), true)
+            .is_empty());
+
+        assert!(!saves.try_save(key, start + Duration::seconds(11)));
+        assert_eq!(
+            saves.take_pending(start + Duration::seconds(12), true),
+            vec![key]
+        );
+    }
+
+    async fn unsuccessful_request(status: u16) -> serenity::Error {
+        let response = http::Response::builder()
+            .status(status)
+            .body(r#"{"code":50013,"message":"Missing Permissions"}"#)
+            .unwrap();
+        let response = ErrorResponse::from_response(response.into()).await;
+
+        serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(response)))
+    }
+
+    #[tokio::test]
+    async fn permission_denied() {
+        let err = serenity::Error::Model(ModelError::InvalidPermissions(
+            Permissions::MANAGE_NICKNAMES,
+        ));
+        let err = NicknameDbError::from(err);
+        assert!(matches!(err, NicknameDbError::PermissionDenied(_)));
+        assert!(err.source().is_some());
+
+        let err = NicknameDbError::from(unsuccessful_request(403).await);
+        assert!(matches!(err, NicknameDbError::PermissionDenied(_)));
+
+        let err = NicknameDbError::from(unsuccessful_request(500).await);
+        assert!(matches!(err, NicknameDbError::Serenity(_)));
+
+        let err = NicknameDbError::from(serenity::Error::Other("connection reset"));
+        assert!(matches!(err, NicknameDbError::Serenity(_)));
+    }
+
+    #[cfg(feature = "file")]
+    fn persistence_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("nicknamedb-{}-{}.ndjson", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn persistence() {
+        let path = persistence_path("persistence");
+        let db = NicknameDb::new('^')
+            .unwrap()
+            .persistence(path.clone())
+            .unwrap();
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        first.lock().await.insert('A', "FOO").await.unwrap();
+        drop(first);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+
+        assert_eq!(db.evict_stale(Duration::milliseconds(25)).await, 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        db.persist().await.unwrap();
+
+        let db = NicknameDb::new('^')
+            .unwrap()
+            .persistence(path.clone())
+            .unwrap();
+        let documents = db.documents.lock().await;
+        assert_eq!(documents.len(), 2);
+        assert_eq!(
+            documents[&(GuildId(1), UserId(1))].lock().await.name,
+            "menfie ^AFOO"
+        );
+        assert_eq!(documents[&(GuildId(1), UserId(2))].lock().await.name, "bob");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn invalidate_persisted() {
+        let path = persistence_path("invalidate");
+        let db = NicknameDb::new('^')
+            .unwrap()
+            .persistence(path.clone())
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        db.persist().await.unwrap();
+
+        db.invalidate_persisted(UserId(1)).await.unwrap();
+        db.persist().await.unwrap();
+
+        let db = NicknameDb::new('^')
+            .unwrap()
+            .persistence(path.clone())
+            .unwrap();
+        let documents = db.documents.lock().await;
+        assert_eq!(documents.len(), 1);
+        assert!(documents.contains_key(&(GuildId(1), UserId(2))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn persist_invalidate() {
+        let path = persistence_path("persist-invalidate");
+        let db = NicknameDb::with_persistence('^', path.clone()).unwrap();
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        db.persist().await.unwrap();
+
+        // The persist waits on the locked document with the file held, and the
+        // invalidation goes through once it has written its snapshot.
+        let guard = first.lock().await;
+        let release = async move {
+            tokio::task::yield_now().await;
+            drop(guard);
+        };
+        let (persisted, invalidated, ()) =
+            futures::join!(db.persist(), db.invalidate_persisted(UserId(1)), release);
+        persisted.unwrap();
+        invalidated.unwrap();
+
+        let db = NicknameDb::with_persistence('^', path.clone()).unwrap();
+        let documents = db.documents.lock().await;
+        assert_eq!(documents.len(), 1);
+        assert!(documents.contains_key(&(GuildId(1), UserId(2))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn evict_unwritable() {
+        let path = std::env::temp_dir()
+            .join(format!("nicknamedb-missing-{}", std::process::id()))
+            .join("documents.ndjson");
+        let db = NicknameDb::new('^')
+            .unwrap()
+            .capacity(1)
+            .persistence(path)
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+
+        assert_eq!(db.documents.lock().await.len(), 2);
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn parse_persisted_out_of_range() {
+        let document = Document::try_new("menfie".to_owned(), '^').unwrap();
+        let line = persisted_line((GuildId(1), UserId(1)), &document).unwrap();
+        let mut record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        record["last_access"] = i64::MAX.into();
+
+        let err = parse_persisted(&record.to_string()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn capacity_load() {
+        let path = persistence_path("capacity-load");
+        let db = NicknameDb::with_persistence('^', path.clone()).unwrap();
+        db.cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap()
+            .lock()
+            .await
+            .set_last_accessed(Utc::now() - Duration::hours(1));
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        db.persist().await.unwrap();
+
+        let loaded = vec![
+            NicknameDb::with_capacity('^', 1)
+                .unwrap()
+                .persistence(path.clone())
+                .unwrap(),
+            NicknameDb::with_persistence('^', path.clone())
+                .unwrap()
+                .capacity(1),
+        ];
+        for db in loaded {
+            let documents = db.documents.lock().await;
+            assert_eq!(documents.len(), 1);
+            assert!(documents.contains_key(&(GuildId(1), UserId(2))));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn capacity_persistence() {
+        let path = persistence_path("capacity");
+        let db = NicknameDb::new('^')
+            .unwrap()
+            .capacity(1)
+            .persistence(path.clone())
+            .unwrap();
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        first.lock().await.insert('A', "FOO").await.unwrap();
+        drop(first);
+        db.cached_document(GuildId(1), UserId(2), || "bob".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(db.documents.lock().await.len(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        let db = NicknameDb::new('^')
+            .unwrap()
+            .persistence(path.clone())
+            .unwrap()
+            .capacity(1);
+        let first = db
+            .cached_document(GuildId(1), UserId(1), || "menfie".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(first.lock().await.name, "menfie ^AFOO");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn validate_nickname() {
+        assert_eq!(super::validate_nickname("menfie ^AFOO"), Ok(()));
+        assert_eq!(
+            super::validate_nickname(&"x".repeat(MAX_NICKNAME_LEN + 2)),
+            Err(DocumentError::NicknameTooLong {
+                length: MAX_NICKNAME_LEN + 2,
+                excess: 2
+            })
+        );
+    }
+}
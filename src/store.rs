@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use async_trait::async_trait;
+use futures::lock::Mutex;
+
+use crate::{Document, DocumentError};
+
+#[derive(Debug)]
+pub enum StoreError {
+    Document(DocumentError),
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Document(err) => fmt::Display::fmt(err, f),
+            StoreError::Backend(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StoreError::Document(err) => Some(err),
+            StoreError::Backend(err) => Some(err.as_ref()),
+        }
+    }
+}
+
+impl From<DocumentError> for StoreError {
+    fn from(err: DocumentError) -> Self {
+        StoreError::Document(err)
+    }
+}
+
+/// Somewhere encoded names can be kept, keyed by an id that only means
+/// something to the store.
+#[async_trait]
+pub trait Store {
+    async fn load(&self, id: &str) -> Option<String>;
+    async fn save(&self, id: &str, name: &str) -> Result<(), StoreError>;
+}
+
+/// Keeps names in memory, mostly useful for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    names: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn load(&self, id: &str) -> Option<String> {
+        self.names.lock().await.get(id).cloned()
+    }
+
+    async fn save(&self, id: &str, name: &str) -> Result<(), StoreError> {
+        self.names
+            .lock()
+            .await
+            .insert(id.to_owned(), name.to_owned());
+
+        Ok(())
+    }
+}
+
+impl Document {
+    /// Parses the name stored under `id`, or returns `None` if there is none.
+    pub async fn load<S: Store + ?Sized>(
+        store: &S,
+        id: &str,
+        prefix: char,
+    ) -> Result<Option<Self>, StoreError> {
+        match store.load(id).await {
+            Some(name) => Ok(Some(Document::try_from_nickname(name, prefix)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn save<S: Store + ?Sized>(&self, store: &S, id: &str) -> Result<(), StoreError> {
+        store.save(id, &self.name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static_assertions::assert_impl_all!(MemoryStore: Send, Sync);
+
+    #[tokio::test]
+    async fn round_trip() {
+        let store = MemoryStore::new();
+        assert!(Document::load(&store, "1", '^').await.unwrap().is_none());
+
+        let mut document = Document::try_new("menfie".to_owned(), '^').unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        document.save(&store, "1").await.unwrap();
+        assert_eq!(store.load("1").await.as_deref(), Some("menfie ^AFOO"));
+
+        let loaded = Document::load(&store, "1", '^').await.unwrap().unwrap();
+        assert_eq!(loaded, document);
+        assert_eq!(loaded.fetch('A').await.as_deref(), Some("FOO"));
+    }
+
+    #[tokio::test]
+    async fn edit_flow() {
+        let store = MemoryStore::new();
+        store.save("1", "menfie").await.unwrap();
+
+        let mut document = Document::load(&store, "1", '^').await.unwrap().unwrap();
+        document.insert('A', "FOO").await.unwrap();
+        document.insert('b', "BAR").await.unwrap();
+        document.delete::<String>('A', None).await.unwrap();
+        document.save(&store, "1").await.unwrap();
+
+        assert_eq!(store.load("1").await.as_deref(), Some("menfie ^bBAR"));
+        assert_eq!(store.load("2").await, None);
+    }
+
+    #[tokio::test]
+    async fn load_malformed() {
+        let store = MemoryStore::new();
+        store.save("1", "menfie ^").await.unwrap();
+        assert!(matches!(
+            Document::load(&store, "1", '^').await,
+            Err(StoreError::Document(DocumentError::Malformed(_)))
+        ));
+    }
+}